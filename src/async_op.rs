@@ -0,0 +1,32 @@
+use crate::error::{Error, Result};
+use std::sync::mpsc;
+use std::time::Duration;
+use windows::core::RuntimeType;
+use windows::Foundation::{AsyncOperationCompletedHandler, AsyncStatus, IAsyncOperation};
+
+/// Bounded replacement for `IAsyncOperation::get`, which blocks forever when
+/// the Bluetooth stack (or Wine's bridge to it) never completes the call.
+pub trait AsyncOperationExt<T> {
+    /// Waits up to `timeout` for the result; on expiry the operation is
+    /// cancelled and `Error::Timeout` is returned.
+    fn get_timeout(&self, timeout: Duration) -> Result<T>;
+}
+
+impl<T: RuntimeType + 'static> AsyncOperationExt<T> for IAsyncOperation<T> {
+    fn get_timeout(&self, timeout: Duration) -> Result<T> {
+        if self.Status()? == AsyncStatus::Started {
+            let (tx, rx) = mpsc::channel();
+            self.SetCompleted(&AsyncOperationCompletedHandler::new(move |_sender, _status| {
+                let _ = tx.send(());
+                Ok(())
+            }))?;
+
+            if rx.recv_timeout(timeout).is_err() {
+                let _ = self.Cancel();
+                return Err(Error::Timeout);
+            }
+        }
+
+        Ok(self.GetResults()?)
+    }
+}
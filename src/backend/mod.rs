@@ -0,0 +1,240 @@
+use crate::error::Result;
+use crate::state::STATE;
+use std::collections::HashSet;
+use std::sync::Arc;
+
+pub mod sim;
+#[cfg(windows)]
+mod winrt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Advertisement {
+    pub address: u64,
+    pub name: String,
+    pub rssi: i32,
+    pub data: AdvertisementData,
+}
+
+/// Payload of an advertisement (or scan response) beyond name and RSSI.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AdvertisementData {
+    pub tx_power: Option<i16>,
+    pub manufacturer_data: Vec<ManufacturerData>,
+    pub service_uuids: Vec<String>,
+    /// Every AD structure of the packet, including the ones above.
+    pub data_sections: Vec<DataSection>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManufacturerData {
+    pub company_id: u16,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DataSection {
+    pub data_type: u8,
+    pub data: Vec<u8>,
+}
+
+impl AdvertisementData {
+    /// Folds a newer packet into what is known about the device. Advertising
+    /// packets and scan responses carry different fields, so only the fields
+    /// present in `newer` replace the stored ones.
+    pub fn merge(&mut self, newer: AdvertisementData) {
+        if newer.tx_power.is_some() {
+            self.tx_power = newer.tx_power;
+        }
+        if !newer.manufacturer_data.is_empty() {
+            self.manufacturer_data = newer.manufacturer_data;
+        }
+        if !newer.service_uuids.is_empty() {
+            self.service_uuids = newer.service_uuids;
+        }
+        // A packet may repeat a type (e.g. several manufacturer sections), so
+        // drop the replaced types once and keep every section of `newer`.
+        let types: HashSet<u8> = newer.data_sections.iter().map(|s| s.data_type).collect();
+        self.data_sections.retain(|s| !types.contains(&s.data_type));
+        self.data_sections.extend(newer.data_sections);
+    }
+
+    /// Data sections in on-air AD format: `[length, type, data...]` each,
+    /// where `length` counts the type byte and the data.
+    pub fn encode_data_sections(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        for section in &self.data_sections {
+            let data = &section.data[..section.data.len().min(254)];
+            out.push(data.len() as u8 + 1);
+            out.push(section.data_type);
+            out.extend_from_slice(data);
+        }
+        out
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Service {
+    pub uuid: String,
+    pub characteristics: Vec<String>,
+}
+
+pub const PROPERTY_READ: u32 = 0x02;
+pub const PROPERTY_WRITE_WITHOUT_RESPONSE: u32 = 0x04;
+pub const PROPERTY_WRITE: u32 = 0x08;
+pub const PROPERTY_NOTIFY: u32 = 0x10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteType {
+    Auto,
+    WithResponse,
+    WithoutResponse,
+}
+
+impl WriteType {
+    pub fn from_raw(value: i32) -> Option<Self> {
+        match value {
+            0 => Some(WriteType::Auto),
+            1 => Some(WriteType::WithResponse),
+            2 => Some(WriteType::WithoutResponse),
+            _ => None,
+        }
+    }
+
+    /// Picks a concrete write type from the characteristic's GATT property
+    /// bits. Acknowledged writes are preferred whenever they are supported.
+    pub fn resolve(self, properties: u32) -> Self {
+        match self {
+            WriteType::Auto => {
+                if properties & PROPERTY_WRITE == 0 && properties & PROPERTY_WRITE_WITHOUT_RESPONSE != 0 {
+                    WriteType::WithoutResponse
+                } else {
+                    WriteType::WithResponse
+                }
+            }
+            other => other,
+        }
+    }
+}
+
+/// The Bluetooth stack the plugin drives. Implementations report asynchronous
+/// events back through `scan::handle_advertisement`, `scan::handle_scan_stopped`,
+/// `connection::handle_connection_status` and `client::handle_value_changed`,
+/// tagging connection events with the `connection::generation()` that was
+/// current when the connection or subscription was set up.
+pub trait BleBackend: Send + Sync {
+    fn open(&self) -> Result<()>;
+    fn close(&self);
+
+    fn start_scan(&self) -> Result<()>;
+    fn stop_scan(&self);
+
+    fn connect(&self, address: u64) -> Result<()>;
+    fn disconnect(&self);
+
+    fn discover_services(&self) -> Result<Vec<Service>>;
+    fn mtu(&self) -> Result<u16>;
+    fn read(&self, uuid: &str) -> Result<Vec<u8>>;
+    fn write(&self, uuid: &str, data: &[u8], write_type: WriteType) -> Result<()>;
+    fn subscribe(&self, uuid: &str) -> Result<()>;
+    fn unsubscribe(&self, uuid: &str) -> Result<()>;
+}
+
+pub fn default_backend() -> Arc<dyn BleBackend> {
+    let requested = std::env::var("SQUAREGOLF_BLE_BACKEND").unwrap_or_default();
+
+    #[cfg(windows)]
+    {
+        if !requested.eq_ignore_ascii_case("sim") {
+            return Arc::new(winrt::WinRtBackend);
+        }
+    }
+    #[cfg(not(windows))]
+    {
+        let _ = requested;
+    }
+
+    Arc::new(sim::SimBackend::new(vec![sim::SimPeripheral::squaregolf()]))
+}
+
+/// Replaces the backend, e.g. with a `SimBackend` scripted for one test.
+#[cfg(test)]
+pub fn install(backend: Arc<dyn BleBackend>) {
+    STATE.lock().unwrap().backend = backend;
+}
+
+pub fn current() -> Arc<dyn BleBackend> {
+    let state = STATE.lock().unwrap();
+    state.backend.clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn section(data_type: u8, data: &[u8]) -> DataSection {
+        DataSection { data_type, data: data.to_vec() }
+    }
+
+    #[test]
+    fn merge_keeps_fields_missing_from_newer_packet() {
+        let mut known = AdvertisementData {
+            tx_power: Some(-4),
+            manufacturer_data: vec![ManufacturerData { company_id: 0xFFFF, data: vec![0x01] }],
+            service_uuids: vec!["86602100-6b7e-439a-bdd1-489a3213e9bb".to_string()],
+            data_sections: vec![section(0x01, &[0x06]), section(0xFF, &[0xFF, 0xFF, 0x01])],
+        };
+
+        known.merge(AdvertisementData {
+            data_sections: vec![section(0x09, b"SquareGolf"), section(0x01, &[0x04])],
+            ..Default::default()
+        });
+
+        assert_eq!(known.tx_power, Some(-4));
+        assert_eq!(known.manufacturer_data.len(), 1);
+        assert_eq!(known.service_uuids.len(), 1);
+        assert_eq!(
+            known.data_sections,
+            [section(0xFF, &[0xFF, 0xFF, 0x01]), section(0x09, b"SquareGolf"), section(0x01, &[0x04])]
+        );
+    }
+
+    #[test]
+    fn merge_keeps_repeated_types_of_newer_packet() {
+        let mut known = AdvertisementData {
+            data_sections: vec![section(0x01, &[0x06]), section(0xFF, &[0x4C, 0x00])],
+            ..Default::default()
+        };
+
+        known.merge(AdvertisementData {
+            data_sections: vec![section(0xFF, &[0xFF, 0xFF, 0x01]), section(0xFF, &[0xFF, 0xFF, 0x02])],
+            ..Default::default()
+        });
+
+        assert_eq!(
+            known.data_sections,
+            [section(0x01, &[0x06]), section(0xFF, &[0xFF, 0xFF, 0x01]), section(0xFF, &[0xFF, 0xFF, 0x02])]
+        );
+    }
+
+    #[test]
+    fn encodes_data_sections_in_ad_format() {
+        let data = AdvertisementData {
+            data_sections: vec![section(0x01, &[0x06]), section(0x0A, &[0xFC]), section(0x09, b"SG")],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            data.encode_data_sections(),
+            [0x02, 0x01, 0x06, 0x02, 0x0A, 0xFC, 0x03, 0x09, b'S', b'G']
+        );
+    }
+
+    #[test]
+    fn resolves_write_type_from_properties() {
+        let both = PROPERTY_WRITE | PROPERTY_WRITE_WITHOUT_RESPONSE;
+        assert_eq!(WriteType::Auto.resolve(both), WriteType::WithResponse);
+        assert_eq!(WriteType::Auto.resolve(PROPERTY_WRITE_WITHOUT_RESPONSE), WriteType::WithoutResponse);
+        assert_eq!(WriteType::WithoutResponse.resolve(PROPERTY_WRITE), WriteType::WithoutResponse);
+        assert_eq!(WriteType::from_raw(3), None);
+    }
+}
@@ -0,0 +1,423 @@
+use super::{
+    Advertisement, AdvertisementData, BleBackend, DataSection, ManufacturerData, Service,
+    WriteType, PROPERTY_NOTIFY, PROPERTY_READ, PROPERTY_WRITE, PROPERTY_WRITE_WITHOUT_RESPONSE,
+};
+use crate::error::{Error, Result};
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const ADVERTISEMENT_INTERVAL: Duration = Duration::from_millis(100);
+
+const AD_TYPE_FLAGS: u8 = 0x01;
+const AD_TYPE_COMPLETE_128_BIT_UUIDS: u8 = 0x07;
+const AD_TYPE_COMPLETE_LOCAL_NAME: u8 = 0x09;
+const AD_TYPE_TX_POWER_LEVEL: u8 = 0x0A;
+const AD_TYPE_MANUFACTURER_DATA: u8 = 0xFF;
+
+/// Company ID the Bluetooth SIG reserves for testing.
+const TEST_COMPANY_ID: u16 = 0xFFFF;
+
+#[derive(Debug, Clone)]
+pub struct SimPeripheral {
+    pub name: String,
+    pub address: u64,
+    pub rssi: i32,
+    /// When not empty, successive advertisements cycle through these RSSI
+    /// values instead of `rssi`.
+    pub rssi_cycle: Vec<i32>,
+    pub mtu: u16,
+    pub tx_power: Option<i16>,
+    pub manufacturer_data: Vec<ManufacturerData>,
+    pub services: Vec<SimService>,
+    pub notifications: Vec<SimNotification>,
+    pub drop_after: Option<Duration>,
+    /// Stops advertising this long after each scan starts.
+    pub advertise_for: Option<Duration>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SimService {
+    pub uuid: String,
+    pub characteristics: Vec<SimCharacteristic>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SimCharacteristic {
+    pub uuid: String,
+    pub properties: u32,
+    pub value: Vec<u8>,
+}
+
+/// Played in order once the characteristic is subscribed; `delay` is
+/// measured from the previous notification for the same characteristic.
+#[derive(Debug, Clone)]
+pub struct SimNotification {
+    pub uuid: String,
+    pub delay: Duration,
+    pub value: Vec<u8>,
+}
+
+impl SimPeripheral {
+    /// Advertises every service UUID, with the AD structures a real
+    /// peripheral would send for the same fields.
+    fn advertisement(&self, count: usize) -> Advertisement {
+        let service_uuids: Vec<String> = self.services.iter().map(|s| s.uuid.clone()).collect();
+
+        let mut data_sections = vec![
+            DataSection { data_type: AD_TYPE_FLAGS, data: vec![0x06] },
+            DataSection {
+                data_type: AD_TYPE_COMPLETE_LOCAL_NAME,
+                data: self.name.as_bytes().to_vec(),
+            },
+        ];
+        if !service_uuids.is_empty() {
+            data_sections.push(DataSection {
+                data_type: AD_TYPE_COMPLETE_128_BIT_UUIDS,
+                data: service_uuids.iter().flat_map(|uuid| uuid_to_le_bytes(uuid)).collect(),
+            });
+        }
+        if let Some(tx_power) = self.tx_power {
+            data_sections.push(DataSection {
+                data_type: AD_TYPE_TX_POWER_LEVEL,
+                data: vec![tx_power as i8 as u8],
+            });
+        }
+        for m in &self.manufacturer_data {
+            let mut data = m.company_id.to_le_bytes().to_vec();
+            data.extend_from_slice(&m.data);
+            data_sections.push(DataSection { data_type: AD_TYPE_MANUFACTURER_DATA, data });
+        }
+
+        Advertisement {
+            address: self.address,
+            name: self.name.clone(),
+            rssi: match self.rssi_cycle.len() {
+                0 => self.rssi,
+                n => self.rssi_cycle[count % n],
+            },
+            data: AdvertisementData {
+                tx_power: self.tx_power,
+                manufacturer_data: self.manufacturer_data.clone(),
+                service_uuids,
+                data_sections,
+            },
+        }
+    }
+
+    pub fn squaregolf() -> Self {
+        Self {
+            name: crate::scan::DEFAULT_DEVICE_NAME.to_string(),
+            address: 0xC0FFEE000001,
+            rssi: -55,
+            rssi_cycle: Vec::new(),
+            mtu: 23,
+            tx_power: Some(0),
+            manufacturer_data: vec![ManufacturerData {
+                company_id: TEST_COMPANY_ID,
+                data: vec![0x01, 0x00],
+            }],
+            services: vec![SimService {
+                uuid: "86602100-6b7e-439a-bdd1-489a3213e9bb".to_string(),
+                characteristics: vec![
+                    SimCharacteristic {
+                        uuid: "86602101-6b7e-439a-bdd1-489a3213e9bb".to_string(),
+                        properties: PROPERTY_WRITE | PROPERTY_WRITE_WITHOUT_RESPONSE,
+                        value: Vec::new(),
+                    },
+                    SimCharacteristic {
+                        uuid: "86602102-6b7e-439a-bdd1-489a3213e9bb".to_string(),
+                        properties: PROPERTY_READ | PROPERTY_NOTIFY,
+                        value: Vec::new(),
+                    },
+                ],
+            }],
+            notifications: vec![
+                SimNotification {
+                    uuid: "86602102-6b7e-439a-bdd1-489a3213e9bb".to_string(),
+                    delay: Duration::from_millis(500),
+                    value: vec![0x11, 0x01, 0x00, 0x64],
+                },
+                SimNotification {
+                    uuid: "86602102-6b7e-439a-bdd1-489a3213e9bb".to_string(),
+                    delay: Duration::from_millis(1000),
+                    value: vec![0x11, 0x02, 0x01, 0x2c, 0x00, 0xa0, 0x0f],
+                },
+            ],
+            drop_after: None,
+            advertise_for: None,
+        }
+    }
+}
+
+/// 128-bit UUIDs go on air least significant byte first.
+fn uuid_to_le_bytes(uuid: &str) -> Vec<u8> {
+    let hex: String = uuid.chars().filter(|c| *c != '-').collect();
+    let mut bytes: Vec<u8> = (0..hex.len() / 2)
+        .filter_map(|i| u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok())
+        .collect();
+    bytes.reverse();
+    bytes
+}
+
+struct SimState {
+    peripherals: Vec<SimPeripheral>,
+    opened: bool,
+    connected: Option<usize>,
+    subscriptions: HashSet<String>,
+}
+
+pub struct SimBackend {
+    state: Arc<Mutex<SimState>>,
+    scan_generation: Arc<AtomicU64>,
+    connection_generation: Arc<AtomicU64>,
+}
+
+impl SimBackend {
+    pub fn new(peripherals: Vec<SimPeripheral>) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(SimState {
+                peripherals,
+                opened: false,
+                connected: None,
+                subscriptions: HashSet::new(),
+            })),
+            scan_generation: Arc::new(AtomicU64::new(0)),
+            connection_generation: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    fn with_characteristic<T>(
+        &self,
+        uuid: &str,
+        f: impl FnOnce(&mut HashSet<String>, &mut SimCharacteristic) -> T,
+    ) -> Result<T> {
+        let uuid_lower = uuid.to_lowercase();
+        let mut guard = self.state.lock().unwrap();
+        let SimState { peripherals, connected, subscriptions, .. } = &mut *guard;
+
+        let index = connected.ok_or(Error::NotConnected)?;
+
+        let characteristic = peripherals[index]
+            .services
+            .iter_mut()
+            .flat_map(|s| s.characteristics.iter_mut())
+            .find(|c| c.uuid == uuid_lower)
+            .ok_or(Error::CharacteristicNotFound)?;
+
+        Ok(f(subscriptions, characteristic))
+    }
+
+    fn play_notifications(&self, uuid: String) {
+        let script: Vec<SimNotification> = {
+            let state = self.state.lock().unwrap();
+            match state.connected {
+                Some(index) => state.peripherals[index]
+                    .notifications
+                    .iter()
+                    .filter(|n| n.uuid == uuid)
+                    .cloned()
+                    .collect(),
+                None => Vec::new(),
+            }
+        };
+
+        if script.is_empty() {
+            return;
+        }
+
+        let state = self.state.clone();
+        let connection_generation = self.connection_generation.clone();
+        let generation = connection_generation.load(Ordering::SeqCst);
+        let plugin_generation = crate::connection::generation();
+
+        thread::spawn(move || {
+            for notification in script {
+                thread::sleep(notification.delay);
+
+                if connection_generation.load(Ordering::SeqCst) != generation {
+                    return;
+                }
+
+                let subscribed = state.lock().unwrap().subscriptions.contains(&uuid);
+                if !subscribed {
+                    return;
+                }
+
+                crate::client::handle_value_changed(plugin_generation, &uuid, &notification.value);
+            }
+        });
+    }
+
+    fn schedule_drop(&self, after: Duration) {
+        let state = self.state.clone();
+        let connection_generation = self.connection_generation.clone();
+        let generation = connection_generation.load(Ordering::SeqCst);
+        let plugin_generation = crate::connection::generation();
+
+        thread::spawn(move || {
+            thread::sleep(after);
+
+            if connection_generation.load(Ordering::SeqCst) != generation {
+                return;
+            }
+
+            {
+                let mut state = state.lock().unwrap();
+                state.connected = None;
+                state.subscriptions.clear();
+            }
+            connection_generation.fetch_add(1, Ordering::SeqCst);
+
+            crate::connection::handle_connection_status(plugin_generation, 0);
+        });
+    }
+}
+
+impl BleBackend for SimBackend {
+    fn open(&self) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.opened = true;
+        Ok(())
+    }
+
+    fn close(&self) {
+        self.stop_scan();
+        self.disconnect();
+
+        let mut state = self.state.lock().unwrap();
+        state.opened = false;
+    }
+
+    fn start_scan(&self) -> Result<()> {
+        if !self.state.lock().unwrap().opened {
+            return Err(Error::NotInitialized);
+        }
+
+        let generation = self.scan_generation.fetch_add(1, Ordering::SeqCst) + 1;
+        let scan_generation = self.scan_generation.clone();
+        let state = self.state.clone();
+
+        thread::spawn(move || {
+            let started = Instant::now();
+            let mut count = 0;
+
+            while scan_generation.load(Ordering::SeqCst) == generation {
+                let advertisements: Vec<Advertisement> = {
+                    let state = state.lock().unwrap();
+                    state
+                        .peripherals
+                        .iter()
+                        .filter(|p| p.advertise_for.is_none_or(|d| started.elapsed() < d))
+                        .map(|p| p.advertisement(count))
+                        .collect()
+                };
+                count += 1;
+
+                for adv in advertisements {
+                    crate::scan::handle_advertisement(adv);
+                }
+
+                thread::sleep(ADVERTISEMENT_INTERVAL);
+            }
+        });
+
+        Ok(())
+    }
+
+    fn stop_scan(&self) {
+        self.scan_generation.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn connect(&self, address: u64) -> Result<()> {
+        let drop_after = {
+            let mut state = self.state.lock().unwrap();
+            let index = state
+                .peripherals
+                .iter()
+                .position(|p| p.address == address)
+                .ok_or(Error::DeviceNotFound)?;
+            state.connected = Some(index);
+            state.peripherals[index].drop_after
+        };
+
+        self.connection_generation.fetch_add(1, Ordering::SeqCst);
+
+        if let Some(after) = drop_after {
+            self.schedule_drop(after);
+        }
+
+        Ok(())
+    }
+
+    fn disconnect(&self) {
+        {
+            let mut state = self.state.lock().unwrap();
+            state.connected = None;
+            state.subscriptions.clear();
+        }
+        self.connection_generation.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn discover_services(&self) -> Result<Vec<Service>> {
+        let state = self.state.lock().unwrap();
+        let index = state.connected.ok_or(Error::NotConnected)?;
+
+        Ok(state.peripherals[index]
+            .services
+            .iter()
+            .map(|s| Service {
+                uuid: s.uuid.clone(),
+                characteristics: s.characteristics.iter().map(|c| c.uuid.clone()).collect(),
+            })
+            .collect())
+    }
+
+    fn mtu(&self) -> Result<u16> {
+        let state = self.state.lock().unwrap();
+        let index = state.connected.ok_or(Error::NotConnected)?;
+        Ok(state.peripherals[index].mtu)
+    }
+
+    fn read(&self, uuid: &str) -> Result<Vec<u8>> {
+        self.with_characteristic(uuid, |_, c| c.value.clone())
+    }
+
+    fn write(&self, uuid: &str, data: &[u8], write_type: WriteType) -> Result<()> {
+        self.with_characteristic(uuid, |_, c| {
+            let required = match write_type.resolve(c.properties) {
+                WriteType::WithoutResponse => PROPERTY_WRITE_WITHOUT_RESPONSE,
+                _ => PROPERTY_WRITE,
+            };
+
+            if c.properties & required == 0 {
+                // GattCommunicationStatus::ProtocolError
+                return Err(Error::GattStatus(2));
+            }
+
+            c.value = data.to_vec();
+            Ok(())
+        })?
+    }
+
+    fn subscribe(&self, uuid: &str) -> Result<()> {
+        let uuid_lower = uuid.to_lowercase();
+        let newly_subscribed =
+            self.with_characteristic(&uuid_lower, |subs, _| subs.insert(uuid_lower.clone()))?;
+
+        if newly_subscribed {
+            self.play_notifications(uuid_lower);
+        }
+
+        Ok(())
+    }
+
+    fn unsubscribe(&self, uuid: &str) -> Result<()> {
+        let uuid_lower = uuid.to_lowercase();
+        self.with_characteristic(&uuid_lower, |subs, _| {
+            subs.remove(&uuid_lower);
+        })
+    }
+}
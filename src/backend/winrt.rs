@@ -0,0 +1,56 @@
+use super::{BleBackend, Service, WriteType};
+use crate::error::Result;
+use crate::{device, gatt, watcher};
+
+pub struct WinRtBackend;
+
+impl BleBackend for WinRtBackend {
+    fn open(&self) -> Result<()> {
+        watcher::create_watcher()
+    }
+
+    fn close(&self) {
+        watcher::destroy_watcher();
+        device::disconnect();
+    }
+
+    fn start_scan(&self) -> Result<()> {
+        watcher::start_scan()
+    }
+
+    fn stop_scan(&self) {
+        watcher::stop_scan();
+    }
+
+    fn connect(&self, address: u64) -> Result<()> {
+        device::connect(address)
+    }
+
+    fn disconnect(&self) {
+        device::disconnect();
+    }
+
+    fn discover_services(&self) -> Result<Vec<Service>> {
+        gatt::discover_services()
+    }
+
+    fn mtu(&self) -> Result<u16> {
+        gatt::max_pdu_size()
+    }
+
+    fn read(&self, uuid: &str) -> Result<Vec<u8>> {
+        gatt::read_characteristic(uuid)
+    }
+
+    fn write(&self, uuid: &str, data: &[u8], write_type: WriteType) -> Result<()> {
+        gatt::write_characteristic(uuid, data, write_type)
+    }
+
+    fn subscribe(&self, uuid: &str) -> Result<()> {
+        gatt::enable_notification(uuid)
+    }
+
+    fn unsubscribe(&self, uuid: &str) -> Result<()> {
+        gatt::disable_notification(uuid)
+    }
+}
@@ -1,5 +1,10 @@
+use crate::backend::AdvertisementData;
+
 type WideChar = u16;
 
+/// Reported as `tx_power` when the advertisement carries no TX power level.
+pub const TX_POWER_UNKNOWN: i32 = 127;
+
 pub type ScanResultHandler = Option<
     unsafe extern "C" fn(
         addr: *const WideChar,
@@ -10,6 +15,30 @@ pub type ScanResultHandler = Option<
     ),
 >;
 
+/// Scan result with the advertisement payload. `company_id` and
+/// `manufacturer_data` hold the first manufacturer entry (`company_id` -1 when
+/// there is none), `service_uuids` is comma-separated and `data_sections` holds
+/// every AD structure as `[length, type, data...]`.
+pub type ScanResultExHandler = Option<
+    unsafe extern "C" fn(
+        addr: *const WideChar,
+        addr_len: i32,
+        name: *const WideChar,
+        name_len: i32,
+        rssi: i32,
+        tx_power: i32,
+        company_id: i32,
+        manufacturer_data: *const u8,
+        manufacturer_data_len: i32,
+        service_uuids: *const WideChar,
+        service_uuids_len: i32,
+        data_sections: *const u8,
+        data_sections_len: i32,
+    ),
+>;
+
+pub type DeviceLostHandler = Option<unsafe extern "C" fn(addr: *const WideChar, addr_len: i32)>;
+
 pub type ConnectionStateHandler = Option<unsafe extern "C" fn(state: i32)>;
 
 pub type ServiceDiscoveredHandler = Option<
@@ -40,6 +69,23 @@ pub type NotificationHandler = Option<
     ),
 >;
 
+pub type WriteCompleteHandler = Option<
+    unsafe extern "C" fn(
+        uuid: *const WideChar,
+        uuid_len: i32,
+        status: i32,
+    ),
+>;
+
+pub type WriteProgressHandler = Option<
+    unsafe extern "C" fn(
+        uuid: *const WideChar,
+        uuid_len: i32,
+        bytes_written: i32,
+        total_bytes: i32,
+    ),
+>;
+
 pub fn invoke_scan_result(
     handler: ScanResultHandler,
     addr: &str,
@@ -63,6 +109,58 @@ pub fn invoke_scan_result(
     }
 }
 
+pub fn invoke_scan_result_ex(
+    handler: ScanResultExHandler,
+    addr: &str,
+    name: &str,
+    rssi: i32,
+    data: &AdvertisementData,
+) {
+    if let Some(cb) = handler {
+        let mut addr_utf16: Vec<u16> = addr.encode_utf16().collect();
+        addr_utf16.push(0);
+        let mut name_utf16: Vec<u16> = name.encode_utf16().collect();
+        name_utf16.push(0);
+        let mut uuids_utf16: Vec<u16> = data.service_uuids.join(",").encode_utf16().collect();
+        uuids_utf16.push(0);
+
+        let tx_power = data.tx_power.map(i32::from).unwrap_or(TX_POWER_UNKNOWN);
+        let (company_id, manufacturer_data) = match data.manufacturer_data.first() {
+            Some(m) => (m.company_id as i32, m.data.as_slice()),
+            None => (-1, &[][..]),
+        };
+        let sections = data.encode_data_sections();
+
+        unsafe {
+            cb(
+                addr_utf16.as_ptr(),
+                (addr_utf16.len() - 1) as i32,
+                name_utf16.as_ptr(),
+                (name_utf16.len() - 1) as i32,
+                rssi,
+                tx_power,
+                company_id,
+                manufacturer_data.as_ptr(),
+                manufacturer_data.len() as i32,
+                uuids_utf16.as_ptr(),
+                (uuids_utf16.len() - 1) as i32,
+                sections.as_ptr(),
+                sections.len() as i32,
+            );
+        }
+    }
+}
+
+pub fn invoke_device_lost(handler: DeviceLostHandler, addr: &str) {
+    if let Some(cb) = handler {
+        let mut addr_utf16: Vec<u16> = addr.encode_utf16().collect();
+        addr_utf16.push(0);
+        unsafe {
+            cb(addr_utf16.as_ptr(), (addr_utf16.len() - 1) as i32);
+        }
+    }
+}
+
 pub fn invoke_connection_state(handler: ConnectionStateHandler, state: i32) {
     if let Some(cb) = handler {
         unsafe {
@@ -71,6 +169,14 @@ pub fn invoke_connection_state(handler: ConnectionStateHandler, state: i32) {
     }
 }
 
+pub fn invoke_scan_state_changed(handler: ScanStateChangedHandler, state: i32) {
+    if let Some(cb) = handler {
+        unsafe {
+            cb(state);
+        }
+    }
+}
+
 pub fn invoke_service_discovered(
     handler: ServiceDiscoveredHandler,
     service_type: i32,
@@ -106,3 +212,56 @@ pub fn invoke_value_changed(
         }
     }
 }
+
+pub fn invoke_notification(
+    handler: NotificationHandler,
+    uuid: &str,
+    value: &[u8],
+) {
+    if let Some(cb) = handler {
+        let mut uuid_utf16: Vec<u16> = uuid.encode_utf16().collect();
+        uuid_utf16.push(0);
+        unsafe {
+            cb(
+                uuid_utf16.as_ptr(),
+                (uuid_utf16.len() - 1) as i32,
+                value.as_ptr(),
+                value.len() as i32,
+            );
+        }
+    }
+}
+
+pub fn invoke_write_complete(
+    handler: WriteCompleteHandler,
+    uuid: &str,
+    status: i32,
+) {
+    if let Some(cb) = handler {
+        let mut uuid_utf16: Vec<u16> = uuid.encode_utf16().collect();
+        uuid_utf16.push(0);
+        unsafe {
+            cb(uuid_utf16.as_ptr(), (uuid_utf16.len() - 1) as i32, status);
+        }
+    }
+}
+
+pub fn invoke_write_progress(
+    handler: WriteProgressHandler,
+    uuid: &str,
+    bytes_written: i32,
+    total_bytes: i32,
+) {
+    if let Some(cb) = handler {
+        let mut uuid_utf16: Vec<u16> = uuid.encode_utf16().collect();
+        uuid_utf16.push(0);
+        unsafe {
+            cb(
+                uuid_utf16.as_ptr(),
+                (uuid_utf16.len() - 1) as i32,
+                bytes_written,
+                total_bytes,
+            );
+        }
+    }
+}
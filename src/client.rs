@@ -0,0 +1,162 @@
+use crate::backend::{self, WriteType};
+use crate::callbacks::{
+    invoke_notification, invoke_service_discovered, invoke_value_changed, invoke_write_complete,
+    invoke_write_progress,
+};
+use crate::connection::{self, ConnectionState};
+use crate::error::{Error, Result};
+use crate::queue;
+use crate::state::STATE;
+
+const ATT_WRITE_HEADER_SIZE: usize = 3;
+
+/// Runs on the caller's thread, but holds the GATT lock throughout so the
+/// discovery and its auto-subscribes never overlap a queued operation.
+pub fn discover_services() -> Result<()> {
+    let _gatt = queue::exclusive();
+
+    let generation = {
+        let state = STATE.lock().unwrap();
+        if !state.connection_state.is_connected() {
+            return Err(Error::NotConnected);
+        }
+        state.connection_generation
+    };
+
+    if !connection::transition(generation, ConnectionState::Discovering) {
+        return Err(Error::NotConnected);
+    }
+
+    let backend = backend::current();
+    let services = match backend.discover_services() {
+        Ok(services) => services,
+        Err(e) => {
+            connection::transition(generation, ConnectionState::Connected);
+            return Err(e);
+        }
+    };
+
+    if connection::generation() != generation {
+        log::info!("Dropping discovery results from a previous connection");
+        return Err(Error::NotConnected);
+    }
+    log::info!("Discovered {} services", services.len());
+
+    let (handler, auto_subscribe) = {
+        let state = STATE.lock().unwrap();
+        (state.service_discovered_handler, state.config.auto_subscribe.clone())
+    };
+
+    for service in services {
+        invoke_service_discovered(handler, 0, &service.uuid);
+
+        for uuid in &service.characteristics {
+            invoke_service_discovered(handler, 1, uuid);
+
+            if auto_subscribe.contains(&uuid.to_lowercase()) {
+                match subscribe(uuid) {
+                    Ok(_) => log::info!("Auto-subscribed to {}", uuid),
+                    Err(e) => log::warn!("Auto-subscribe to {} failed: {}", uuid, e),
+                }
+            }
+        }
+    }
+
+    connection::transition(generation, ConnectionState::Ready);
+    Ok(())
+}
+
+/// Reads on the caller's thread once no queued operation is running.
+pub fn read(uuid: &str) -> Result<Vec<u8>> {
+    let _gatt = queue::exclusive();
+    backend::current().read(uuid)
+}
+
+/// Enables notifications and remembers the subscription so it can be
+/// restored after an automatic reconnect.
+pub fn subscribe(uuid: &str) -> Result<()> {
+    backend::current().subscribe(uuid)?;
+    STATE.lock().unwrap().subscriptions.insert(uuid.to_lowercase());
+    Ok(())
+}
+
+pub fn unsubscribe(uuid: &str) -> Result<()> {
+    STATE.lock().unwrap().subscriptions.remove(&uuid.to_lowercase());
+    backend::current().unsubscribe(uuid)
+}
+
+/// Writes and reports the outcome through the write-complete callback: 0 on
+/// success, the `GattCommunicationStatus` value on a GATT failure, -1 otherwise.
+pub fn write(uuid: &str, data: &[u8], write_type: WriteType) -> Result<()> {
+    log::debug!("Writing {} bytes to {} ({:?})", data.len(), uuid, write_type);
+    let result = backend::current().write(uuid, data, write_type);
+    report_write_complete(uuid, &result);
+    result
+}
+
+/// Splits `data` into chunks of at most `chunk_size` bytes, capped at the
+/// negotiated ATT MTU (0 means "as large as the MTU allows"). Each chunk is
+/// acknowledged before the next is sent and reported through the progress
+/// callback; the write-complete callback fires once, after the last chunk.
+pub fn write_chunked(uuid: &str, data: &[u8], chunk_size: usize) -> Result<()> {
+    let backend = backend::current();
+
+    let progress_handler = {
+        let state = STATE.lock().unwrap();
+        state.write_progress_handler
+    };
+
+    let result = (|| {
+        let max_chunk_size = (backend.mtu()? as usize)
+            .saturating_sub(ATT_WRITE_HEADER_SIZE)
+            .max(1);
+        let chunk_size = match chunk_size {
+            0 => max_chunk_size,
+            n => n.min(max_chunk_size),
+        };
+        log::debug!("Writing {} bytes to {} in {}-byte chunks", data.len(), uuid, chunk_size);
+
+        let mut written = 0;
+        for chunk in data.chunks(chunk_size) {
+            backend.write(uuid, chunk, WriteType::Auto)?;
+            written += chunk.len();
+            invoke_write_progress(progress_handler, uuid, written as i32, data.len() as i32);
+        }
+
+        Ok(())
+    })();
+
+    report_write_complete(uuid, &result);
+    result
+}
+
+pub fn report_write_complete(uuid: &str, result: &Result<()>) {
+    let status = match result {
+        Ok(_) => 0,
+        Err(Error::GattStatus(status)) => *status,
+        Err(_) => -1,
+    };
+
+    let handler = {
+        let state = STATE.lock().unwrap();
+        state.write_complete_handler
+    };
+
+    invoke_write_complete(handler, uuid, status);
+}
+
+pub fn handle_value_changed(generation: u64, uuid: &str, data: &[u8]) {
+    let (notification_handler, value_changed_handler) = {
+        let state = STATE.lock().unwrap();
+        if state.connection_generation != generation {
+            log::debug!("Ignoring notification from {} of generation {}", uuid, generation);
+            return;
+        }
+        (state.notification_handler, state.value_changed_handler)
+    };
+
+    log::trace!("Notification from {}: {:02x?}", uuid, data);
+
+    invoke_notification(notification_handler, uuid, data);
+    invoke_value_changed(value_changed_handler, uuid, data);
+}
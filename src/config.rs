@@ -0,0 +1,740 @@
+use crate::error::{Error, Result};
+use crate::last_device::AutoConnect;
+use crate::pattern::NamePattern;
+use crate::scan::{FilterMode, ManufacturerFilter, ScanMode, DEFAULT_DEVICE_NAME};
+use std::borrow::Cow;
+use std::path::PathBuf;
+use std::time::Duration;
+
+const CONFIG_ENV_VAR: &str = "SQUAREGOLF_PLUGIN_CONFIG";
+const CONFIG_FILE_NAME: &str = "plugin.ini";
+
+const DEFAULT_AUTO_SUBSCRIBE_UUID: &str = "86602102-6b7e-439a-bdd1-489a3213e9bb";
+const DEFAULT_DEVICE_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_RSSI_WINDOW: usize = 5;
+const DEFAULT_REPORT_INTERVAL: Duration = Duration::from_millis(500);
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_GATT_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_DISCOVERY_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_RECONNECT_ATTEMPTS: u32 = 5;
+const DEFAULT_RECONNECT_DELAY: Duration = Duration::from_millis(500);
+const DEFAULT_RECONNECT_MAX_DELAY: Duration = Duration::from_secs(8);
+const DEFAULT_LOG_MAX_SIZE: u64 = 1024 * 1024;
+const DEFAULT_LOG_MAX_FILES: usize = 3;
+
+/// The `[scan]` keys `_setScanFilter` accepts; the others would be parsed and
+/// then ignored by `ScanFilter::from_config`.
+const SCAN_FILTER_KEYS: [&str; 5] =
+    ["name_filters", "name_patterns", "service_uuids", "manufacturer_filters", "filter_mode"];
+
+/// Settings read from `plugin.ini` next to the DLL, or from the file named by
+/// `SQUAREGOLF_PLUGIN_CONFIG`. The format is INI; simple TOML (quoted strings
+/// with their escapes, `[...]` arrays) parses the same way.
+///
+/// ```ini
+/// [scan]
+/// name_filters = SquareGolf, SG-Pro
+/// name_patterns = "^SquareGolf-[0-9A-F]+$"
+/// service_uuids = 86602100-6b7e-439a-bdd1-489a3213e9bb
+/// manufacturer_filters = FFFF:01
+/// filter_mode = any
+/// timeout_ms = 0
+/// auto_connect = false
+/// device_timeout_ms = 10000
+/// rssi_window = 5
+/// report_interval_ms = 500
+/// min_rssi = -90
+/// mode = passive
+/// sampling_interval_ms = 100
+/// allow_extended = false
+///
+/// [connect]
+/// timeout_ms = 10000
+/// auto_reconnect = false
+/// reconnect_attempts = 5
+/// reconnect_delay_ms = 500
+/// reconnect_max_delay_ms = 8000
+/// auto_connect_last = off
+///
+/// [gatt]
+/// auto_subscribe = 86602102-6b7e-439a-bdd1-489a3213e9bb
+/// timeout_ms = 5000
+/// discovery_timeout_ms = 30000
+///
+/// [log]
+/// level = info
+/// file = C:\Users\me\squaregolf.log
+/// max_size_kb = 1024
+/// max_files = 3
+/// ```
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub name_filters: Vec<String>,
+    pub name_patterns: Vec<NamePattern>,
+    /// Lowercase 128-bit service UUIDs; 16-bit ones are expanded on parse.
+    pub service_filters: Vec<String>,
+    pub manufacturer_filters: Vec<ManufacturerFilter>,
+    pub filter_mode: FilterMode,
+    pub auto_subscribe: Vec<String>,
+    pub scan_timeout: Option<Duration>,
+    /// Connect to the strongest matching device when a timed scan ends.
+    pub scan_auto_connect: bool,
+    /// Devices not heard from for this long are dropped from the scan list
+    /// and reported through the device-lost callback.
+    pub device_timeout: Option<Duration>,
+    /// Number of RSSI samples averaged per device; 1 reports raw values.
+    pub rssi_window: usize,
+    /// Minimum time between two scan results for the same device.
+    pub report_interval: Duration,
+    /// Devices with a smoothed RSSI below this are not reported.
+    pub min_rssi: Option<i32>,
+    #[cfg_attr(not(windows), allow(dead_code))]
+    pub scan_mode: ScanMode,
+    /// How often the watcher reports a device it keeps hearing; `None` leaves
+    /// the stack's default.
+    #[cfg_attr(not(windows), allow(dead_code))]
+    pub sampling_interval: Option<Duration>,
+    /// Also receive Bluetooth 5 extended advertisements.
+    #[cfg_attr(not(windows), allow(dead_code))]
+    pub allow_extended_advertisements: bool,
+    #[cfg_attr(not(windows), allow(dead_code))]
+    pub connect_timeout: Duration,
+    /// Limit for each GATT request (discovery, read, write, CCCD write).
+    pub gatt_timeout: Duration,
+    /// How long `_discoverServices` waits for a discovery another caller
+    /// started, which makes several GATT requests and may first wait for a
+    /// queued connect.
+    pub discovery_timeout: Duration,
+    pub auto_reconnect: bool,
+    pub reconnect_attempts: u32,
+    /// Delay before the first reconnect attempt; doubles on every further
+    /// attempt up to `reconnect_max_delay`.
+    pub reconnect_delay: Duration,
+    pub reconnect_max_delay: Duration,
+    /// Connect straight to the last connected device on `_open` or on the
+    /// first `_startScan` or `_startScanWithTimeout`.
+    pub auto_connect_last: AutoConnect,
+    pub log_level: log::LevelFilter,
+    pub log_file: Option<PathBuf>,
+    pub log_max_size: u64,
+    pub log_max_files: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            name_filters: vec![DEFAULT_DEVICE_NAME.to_string()],
+            name_patterns: Vec::new(),
+            service_filters: Vec::new(),
+            manufacturer_filters: Vec::new(),
+            filter_mode: FilterMode::Any,
+            auto_subscribe: vec![DEFAULT_AUTO_SUBSCRIBE_UUID.to_string()],
+            scan_timeout: None,
+            scan_auto_connect: false,
+            device_timeout: Some(DEFAULT_DEVICE_TIMEOUT),
+            rssi_window: DEFAULT_RSSI_WINDOW,
+            report_interval: DEFAULT_REPORT_INTERVAL,
+            min_rssi: None,
+            scan_mode: ScanMode::Passive,
+            sampling_interval: None,
+            allow_extended_advertisements: false,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            gatt_timeout: DEFAULT_GATT_TIMEOUT,
+            discovery_timeout: DEFAULT_DISCOVERY_TIMEOUT,
+            auto_reconnect: false,
+            reconnect_attempts: DEFAULT_RECONNECT_ATTEMPTS,
+            reconnect_delay: DEFAULT_RECONNECT_DELAY,
+            reconnect_max_delay: DEFAULT_RECONNECT_MAX_DELAY,
+            auto_connect_last: AutoConnect::Off,
+            log_level: log::LevelFilter::Info,
+            log_file: None,
+            log_max_size: DEFAULT_LOG_MAX_SIZE,
+            log_max_files: DEFAULT_LOG_MAX_FILES,
+        }
+    }
+}
+
+impl Config {
+    /// Parses `plugin.ini`, returning the lines that were ignored alongside
+    /// the settings. They are logged by the caller once logging is set up,
+    /// which itself depends on the settings.
+    pub fn parse(text: &str) -> (Self, Vec<String>) {
+        let mut config = Config::default();
+        let rejected = config.read(text, "", None);
+        (config, rejected)
+    }
+
+    /// Parses a filter passed to `_setScanFilter`: the filter keys of `[scan]`,
+    /// one per line, without section headers. Criteria it does not mention
+    /// are left empty, not defaulted. Any other line rejects the whole filter,
+    /// since dropping an entry could leave a filter that lets every device
+    /// through.
+    pub fn parse_scan_filter(text: &str) -> Result<Self> {
+        let mut config = Config {
+            name_filters: Vec::new(),
+            ..Config::default()
+        };
+        let rejected = config.read(text, "scan", Some(&SCAN_FILTER_KEYS));
+        if !rejected.is_empty() {
+            log::warn!("Invalid scan filter lines: {:?}", rejected);
+            return Err(Error::InvalidArgument);
+        }
+        Ok(config)
+    }
+
+    /// Applies every line of `text`, starting in `section`, and returns the
+    /// lines that were not understood. With `keys`, only those keys of the
+    /// starting section are accepted and section headers are rejected.
+    fn read(&mut self, text: &str, section: &str, keys: Option<&[&str]>) -> Vec<String> {
+        let mut section = section.to_string();
+        let mut rejected = Vec::new();
+
+        for line in text.lines() {
+            let line = strip_comment(line).trim();
+
+            if line.is_empty() {
+                continue;
+            }
+
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                if keys.is_some() {
+                    rejected.push(line.to_string());
+                } else {
+                    section = name.trim().to_lowercase();
+                }
+                continue;
+            }
+
+            let applied = match line.split_once('=') {
+                Some((key, value)) => {
+                    let key = key.trim().to_lowercase();
+                    keys.is_none_or(|keys| keys.contains(&key.as_str()))
+                        && self.apply(&section, &key, value.trim())
+                }
+                None => false,
+            };
+            if !applied {
+                rejected.push(line.to_string());
+            }
+        }
+
+        rejected
+    }
+
+    /// Applies one `key = value` line. Returns false, leaving the setting as
+    /// it was, when the key is unknown or the value (or any entry of a list)
+    /// does not parse.
+    fn apply(&mut self, section: &str, key: &str, value: &str) -> bool {
+        match (section, key) {
+            ("scan", "name_filters") => self.name_filters = parse_list(value),
+            ("scan", "name_patterns") => {
+                let patterns = parse_list(value).iter().map(|p| NamePattern::parse(p)).collect();
+                let Some(patterns) = patterns else { return false };
+                self.name_patterns = patterns;
+            }
+            ("scan", "service_uuids") => {
+                self.service_filters = parse_list(value)
+                    .iter()
+                    .map(|uuid| expand_uuid(uuid))
+                    .collect();
+            }
+            ("scan", "manufacturer_filters") => {
+                let filters = parse_list(value).iter().map(|m| ManufacturerFilter::parse(m)).collect();
+                let Some(filters) = filters else { return false };
+                self.manufacturer_filters = filters;
+            }
+            ("scan", "filter_mode") => {
+                let Some(mode) = FilterMode::parse(&unquote(value)) else { return false };
+                self.filter_mode = mode;
+            }
+            ("scan", "timeout_ms") => {
+                let Some(ms) = parse_millis(value) else { return false };
+                self.scan_timeout = (!ms.is_zero()).then_some(ms);
+            }
+            ("scan", "auto_connect") => {
+                let Some(enabled) = parse_bool(value) else { return false };
+                self.scan_auto_connect = enabled;
+            }
+            ("scan", "device_timeout_ms") => {
+                let Some(ms) = parse_millis(value) else { return false };
+                self.device_timeout = (!ms.is_zero()).then_some(ms);
+            }
+            ("scan", "rssi_window") => {
+                let Ok(n) = unquote(value).parse::<usize>() else { return false };
+                self.rssi_window = n.max(1);
+            }
+            ("scan", "report_interval_ms") => {
+                let Some(ms) = parse_millis(value) else { return false };
+                self.report_interval = ms;
+            }
+            ("scan", "min_rssi") => {
+                let value = unquote(value);
+                self.min_rssi = if value.is_empty() { None } else { value.parse().ok() };
+                return value.is_empty() || self.min_rssi.is_some();
+            }
+            ("scan", "mode") => {
+                let Some(mode) = ScanMode::parse(&unquote(value)) else { return false };
+                self.scan_mode = mode;
+            }
+            ("scan", "sampling_interval_ms") => {
+                let value = unquote(value);
+                self.sampling_interval = if value.is_empty() { None } else { parse_millis(&value) };
+                return value.is_empty() || self.sampling_interval.is_some();
+            }
+            ("scan", "allow_extended") => {
+                let Some(enabled) = parse_bool(value) else { return false };
+                self.allow_extended_advertisements = enabled;
+            }
+            ("connect", "timeout_ms") => {
+                let Some(ms) = parse_millis(value) else { return false };
+                self.connect_timeout = ms;
+            }
+            ("connect", "auto_reconnect") => {
+                let Some(enabled) = parse_bool(value) else { return false };
+                self.auto_reconnect = enabled;
+            }
+            ("connect", "reconnect_attempts") => {
+                let Ok(n) = unquote(value).parse() else { return false };
+                self.reconnect_attempts = n;
+            }
+            ("connect", "reconnect_delay_ms") => {
+                let Some(ms) = parse_millis(value) else { return false };
+                self.reconnect_delay = ms;
+            }
+            ("connect", "reconnect_max_delay_ms") => {
+                let Some(ms) = parse_millis(value) else { return false };
+                self.reconnect_max_delay = ms;
+            }
+            ("connect", "auto_connect_last") => {
+                let Some(mode) = AutoConnect::parse(&unquote(value)) else { return false };
+                self.auto_connect_last = mode;
+            }
+            ("gatt", "timeout_ms") => {
+                let Some(ms) = parse_millis(value) else { return false };
+                self.gatt_timeout = ms;
+            }
+            ("gatt", "discovery_timeout_ms") => {
+                let Some(ms) = parse_millis(value) else { return false };
+                self.discovery_timeout = ms;
+            }
+            ("gatt", "auto_subscribe") => {
+                self.auto_subscribe = parse_list(value)
+                    .into_iter()
+                    .map(|uuid| uuid.to_lowercase())
+                    .collect();
+            }
+            ("log", "level") => {
+                let Ok(level) = unquote(value).parse() else { return false };
+                self.log_level = level;
+            }
+            ("log", "file") => {
+                let path = unquote(value);
+                self.log_file = (!path.is_empty()).then(|| PathBuf::from(&*path));
+            }
+            ("log", "max_size_kb") => {
+                let Ok(kb) = unquote(value).parse::<u64>() else { return false };
+                self.log_max_size = kb.saturating_mul(1024);
+            }
+            ("log", "max_files") => {
+                let Ok(n) = unquote(value).parse() else { return false };
+                self.log_max_files = n;
+            }
+            _ => return false,
+        }
+        true
+    }
+}
+
+/// Cuts a `#` or `;` comment off `line`. Only one at the start of the line or
+/// after whitespace, and outside quotes, starts a comment, so values such as
+/// `SG#1` or a pattern containing `;` are kept whole.
+fn strip_comment(line: &str) -> &str {
+    for (i, c) in unquoted(line) {
+        if matches!(c, '#' | ';') && line[..i].chars().next_back().is_none_or(char::is_whitespace) {
+            return &line[..i];
+        }
+    }
+    line
+}
+
+/// The characters of `text` outside quoted strings, with their byte offsets.
+/// A quote only opens a string at the start of a value or list entry, so an
+/// apostrophe inside an unquoted name is taken literally.
+fn unquoted(text: &str) -> impl Iterator<Item = (usize, char)> + '_ {
+    let mut quote = None;
+    let mut escaped = false;
+    let mut entry_start = true;
+
+    text.char_indices().filter(move |&(_, c)| {
+        if let Some(q) = quote {
+            if escaped {
+                escaped = false;
+            } else if q == '"' && c == '\\' {
+                escaped = true;
+            } else if c == q {
+                quote = None;
+            }
+            return false;
+        }
+
+        if entry_start && (c == '"' || c == '\'') {
+            quote = Some(c);
+            entry_start = false;
+            return false;
+        }
+        if !c.is_whitespace() {
+            entry_start = matches!(c, '=' | ',' | '[');
+        }
+        true
+    })
+}
+
+/// Strips the quotes off a value. Like TOML, `'...'` is taken literally and
+/// `"..."` may contain `\\`, `\"`, `\t`, `\n`, `\r`, `\b`, `\f`, `\uXXXX` and
+/// `\UXXXXXXXX` escapes; any other backslash is kept, so an unescaped
+/// Windows path still reads as written.
+fn unquote(value: &str) -> Cow<'_, str> {
+    let value = value.trim();
+    if let Some(inner) = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+        return if inner.contains('\\') { Cow::Owned(unescape(inner)) } else { Cow::Borrowed(inner) };
+    }
+    let literal = value.strip_prefix('\'').and_then(|v| v.strip_suffix('\''));
+    Cow::Borrowed(literal.unwrap_or(value))
+}
+
+fn unescape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(i) = rest.find('\\') {
+        out.push_str(&rest[..i]);
+        rest = &rest[i..];
+
+        let unicode = |digits: usize| {
+            let hex = rest.get(2..2 + digits).filter(|h| h.chars().all(|c| c.is_ascii_hexdigit()))?;
+            let c = char::from_u32(u32::from_str_radix(hex, 16).ok()?)?;
+            Some((c, 2 + digits))
+        };
+        let escape = match rest[1..].chars().next() {
+            Some('\\') => Some(('\\', 2)),
+            Some('"') => Some(('"', 2)),
+            Some('t') => Some(('\t', 2)),
+            Some('n') => Some(('\n', 2)),
+            Some('r') => Some(('\r', 2)),
+            Some('b') => Some(('\u{8}', 2)),
+            Some('f') => Some(('\u{c}', 2)),
+            Some('u') => unicode(4),
+            Some('U') => unicode(8),
+            _ => None,
+        };
+
+        match escape {
+            Some((c, len)) => {
+                out.push(c);
+                rest = &rest[len..];
+            }
+            None => {
+                out.push('\\');
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    out.push_str(rest);
+    out
+}
+
+/// Splits a comma-separated list, optionally in `[...]`. Commas inside quoted
+/// entries belong to the entry.
+fn parse_list(value: &str) -> Vec<String> {
+    let value = value.trim();
+    let value = value
+        .strip_prefix('[')
+        .and_then(|v| v.strip_suffix(']'))
+        .unwrap_or(value);
+
+    let mut entries = Vec::new();
+    let mut start = 0;
+    for (i, c) in unquoted(value) {
+        if c == ',' {
+            entries.push(&value[start..i]);
+            start = i + 1;
+        }
+    }
+    entries.push(&value[start..]);
+
+    entries
+        .into_iter()
+        .map(|entry| unquote(entry).into_owned())
+        .filter(|entry| !entry.is_empty())
+        .collect()
+}
+
+/// Expands a 16-bit UUID such as `180d` to its full Bluetooth base form, which
+/// is how the stack reports service UUIDs.
+fn expand_uuid(uuid: &str) -> String {
+    let uuid = uuid.to_lowercase();
+    if uuid.len() == 4 && uuid.chars().all(|c| c.is_ascii_hexdigit()) {
+        format!("0000{}-0000-1000-8000-00805f9b34fb", uuid)
+    } else {
+        uuid
+    }
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match unquote(value).to_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Some(true),
+        "0" | "false" | "no" | "off" => Some(false),
+        _ => None,
+    }
+}
+
+fn parse_millis(value: &str) -> Option<Duration> {
+    unquote(value).parse().ok().map(Duration::from_millis)
+}
+
+fn config_path() -> Option<PathBuf> {
+    if let Some(path) = std::env::var_os(CONFIG_ENV_VAR) {
+        return Some(PathBuf::from(path));
+    }
+    plugin_dir().map(|dir| dir.join(CONFIG_FILE_NAME))
+}
+
+/// Loads the config file, falling back to defaults when it is missing. Also
+/// returns the lines that were ignored.
+pub fn load() -> (Config, Vec<String>) {
+    config_path()
+        .and_then(|path| std::fs::read_to_string(path).ok())
+        .map(|text| Config::parse(&text))
+        .unwrap_or_default()
+}
+
+/// Directories the plugin may write its state file and log to, in order of
+/// preference: next to `plugin.dll`, then the temp directory.
+pub fn data_dirs() -> Vec<PathBuf> {
+    #[cfg(test)]
+    if let Some(dir) = DATA_DIR.lock().unwrap().clone() {
+        return vec![dir];
+    }
+
+    plugin_dir()
+        .into_iter()
+        .chain(std::iter::once(std::env::temp_dir()))
+        .collect()
+}
+
+#[cfg(test)]
+static DATA_DIR: std::sync::Mutex<Option<PathBuf>> = std::sync::Mutex::new(None);
+
+/// Replaces the data directories with `dir`, e.g. a scratch directory for one
+/// test.
+#[cfg(test)]
+pub fn install_data_dir(dir: PathBuf) {
+    *DATA_DIR.lock().unwrap() = Some(dir);
+}
+
+/// Directory containing `plugin.dll`, which is not the game's working
+/// directory or executable directory.
+#[cfg(windows)]
+pub fn plugin_dir() -> Option<PathBuf> {
+    use windows::core::PCWSTR;
+    use windows::Win32::Foundation::HMODULE;
+    use windows::Win32::System::LibraryLoader::{
+        GetModuleFileNameW, GetModuleHandleExW, GET_MODULE_HANDLE_EX_FLAG_FROM_ADDRESS,
+        GET_MODULE_HANDLE_EX_FLAG_UNCHANGED_REFCOUNT,
+    };
+
+    let mut module = HMODULE::default();
+    let mut buf = vec![0u16; 1024];
+
+    let len = unsafe {
+        GetModuleHandleExW(
+            GET_MODULE_HANDLE_EX_FLAG_FROM_ADDRESS | GET_MODULE_HANDLE_EX_FLAG_UNCHANGED_REFCOUNT,
+            PCWSTR(plugin_dir as *const () as *const u16),
+            &mut module,
+        )
+        .ok()?;
+        GetModuleFileNameW(module, &mut buf) as usize
+    };
+
+    if len == 0 {
+        return None;
+    }
+
+    PathBuf::from(String::from_utf16_lossy(&buf[..len]))
+        .parent()
+        .map(|dir| dir.to_path_buf())
+}
+
+#[cfg(not(windows))]
+pub fn plugin_dir() -> Option<PathBuf> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_keys_keep_defaults() {
+        let (config, _) = Config::parse("");
+        assert_eq!(config.name_filters, [DEFAULT_DEVICE_NAME]);
+        assert_eq!(config.auto_subscribe, [DEFAULT_AUTO_SUBSCRIBE_UUID]);
+        assert_eq!(config.gatt_timeout, DEFAULT_GATT_TIMEOUT);
+        assert_eq!(config.scan_timeout, None);
+        assert_eq!(config.scan_mode, ScanMode::Passive);
+        assert_eq!(config.filter_mode, FilterMode::Any);
+    }
+
+    #[test]
+    fn parses_sections_lists_and_comments() {
+        let (config, _) = Config::parse(
+            "# plugin settings\n\
+             [Scan]\n\
+             name_filters = [\"SquareGolf\", 'SG-Pro']\n\
+             timeout_ms = 3000 ; stop after three seconds\n\
+             min_rssi = -80\n\
+             service_uuids = 180d, 86602100-6B7E-439A-BDD1-489A3213E9BB\n\
+             manufacturer_filters = FFFF:01\n\
+             filter_mode = all\n\
+             mode = active\n\
+             \n\
+             [connect]\n\
+             auto_reconnect = yes\n\
+             reconnect_attempts = 3\n\
+             \n\
+             [gatt]\n\
+             auto_subscribe = 86602102-6B7E-439A-BDD1-489A3213E9BB\n\
+             timeout_ms = 250\n\
+             \n\
+             [log]\n\
+             level = debug\n\
+             max_size_kb = 64\n",
+        );
+
+        assert_eq!(config.name_filters, ["SquareGolf", "SG-Pro"]);
+        assert_eq!(config.scan_timeout, Some(Duration::from_secs(3)));
+        assert_eq!(config.min_rssi, Some(-80));
+        assert_eq!(
+            config.service_filters,
+            ["0000180d-0000-1000-8000-00805f9b34fb", "86602100-6b7e-439a-bdd1-489a3213e9bb"]
+        );
+        assert_eq!(
+            config.manufacturer_filters,
+            [ManufacturerFilter { company_id: 0xFFFF, prefix: vec![0x01] }]
+        );
+        assert_eq!(config.filter_mode, FilterMode::All);
+        assert_eq!(config.scan_mode, ScanMode::Active);
+        assert!(config.auto_reconnect);
+        assert_eq!(config.reconnect_attempts, 3);
+        assert_eq!(config.auto_subscribe, ["86602102-6b7e-439a-bdd1-489a3213e9bb"]);
+        assert_eq!(config.gatt_timeout, Duration::from_millis(250));
+        assert_eq!(config.log_level, log::LevelFilter::Debug);
+        assert_eq!(config.log_max_size, 64 * 1024);
+    }
+
+    #[test]
+    fn comments_need_leading_whitespace() {
+        let (config, _) = Config::parse(
+            "[scan]\n\
+             name_filters = SG#1, SG;2 # trailing comment\n\
+             ;name_filters = disabled\n\
+             name_patterns = ^SG[#;]\\d$\t; another\n",
+        );
+        assert_eq!(config.name_filters, ["SG#1", "SG;2"]);
+        assert!(config.name_patterns[0].is_match("SG;7"));
+    }
+
+    #[test]
+    fn quoted_values_keep_commas_and_comment_characters() {
+        let (config, _) = Config::parse(
+            "[scan]\n\
+             name_filters = \"SG, Pro\", 'SG #2', Bob's Golf ; comment\n\
+             name_patterns = [\"^SG[,-]\"]\n",
+        );
+        assert_eq!(config.name_filters, ["SG, Pro", "SG #2", "Bob's Golf"]);
+        assert_eq!(config.name_patterns.len(), 1);
+        assert!(config.name_patterns[0].is_match("SG-1"));
+        assert!(config.name_patterns[0].is_match("SG,1"));
+    }
+
+    #[test]
+    fn double_quoted_values_take_toml_escapes() {
+        let (config, _) = Config::parse(
+            "[scan]\n\
+             name_patterns = [\"^SG-\\\\d+$\", '^Pro\\d$']\n\
+             name_filters = \"Square\\u0047olf \\\"2\\\"\"\n\
+             [log]\n\
+             file = \"C:\\Users\\me\\sg.log\"\n",
+        );
+        assert!(config.name_patterns[0].is_match("SG-42"));
+        assert!(config.name_patterns[1].is_match("Pro7"));
+        assert_eq!(config.name_filters, ["SquareGolf \"2\""]);
+        assert_eq!(config.log_file, Some(PathBuf::from("C:\\Users\\me\\sg.log")));
+    }
+
+    #[test]
+    fn invalid_values_keep_defaults() {
+        let (config, rejected) = Config::parse(
+            "[scan]\ntimeout_ms = soon\nrssi_window = 0\n[connect]\nauto_reconnect = maybe\n",
+        );
+        assert_eq!(rejected, ["timeout_ms = soon", "auto_reconnect = maybe"]);
+        assert_eq!(config.scan_timeout, None);
+        assert_eq!(config.rssi_window, 1);
+        assert!(!config.auto_reconnect);
+    }
+
+    #[test]
+    fn invalid_list_entries_drop_the_whole_key() {
+        let (config, _) = Config::parse(
+            "[scan]\nname_patterns = ^SG-\\d+$, ^SG[\nmanufacturer_filters = 004C, XYZ\n",
+        );
+        assert!(config.name_patterns.is_empty());
+        assert!(config.manufacturer_filters.is_empty());
+    }
+
+    #[test]
+    fn huge_log_size_saturates() {
+        let (config, rejected) = Config::parse("[log]\nmax_size_kb = 18446744073709551615\n");
+        assert!(rejected.is_empty());
+        assert_eq!(config.log_max_size, u64::MAX);
+    }
+
+    #[test]
+    fn zero_disables_optional_timeouts() {
+        let (config, _) = Config::parse("[scan]\ntimeout_ms = 0\ndevice_timeout_ms = 0\n");
+        assert_eq!(config.scan_timeout, None);
+        assert_eq!(config.device_timeout, None);
+    }
+
+    #[test]
+    fn scan_filter_leaves_unmentioned_criteria_empty() {
+        let config = Config::parse_scan_filter("manufacturer_filters = 004C\nfilter_mode = all").unwrap();
+        assert!(config.name_filters.is_empty());
+        assert!(config.service_filters.is_empty());
+        assert_eq!(config.manufacturer_filters.len(), 1);
+        assert_eq!(config.filter_mode, FilterMode::All);
+    }
+
+    #[test]
+    fn scan_filter_rejects_malformed_entries() {
+        assert!(Config::parse_scan_filter("name_filters = SquareGolf\n# comment\n\n").is_ok());
+        assert_eq!(
+            Config::parse_scan_filter("manufacturer_filters = 004C, XYZ").unwrap_err(),
+            Error::InvalidArgument
+        );
+        assert!(Config::parse_scan_filter("name_patterns = \"^SG[\"").is_err());
+        assert!(Config::parse_scan_filter("filter_mode = most").is_err());
+        assert!(Config::parse_scan_filter("name_filter = SquareGolf").is_err());
+        assert!(Config::parse_scan_filter("SquareGolf").is_err());
+    }
+
+    #[test]
+    fn scan_filter_accepts_only_filter_keys() {
+        assert!(Config::parse_scan_filter("min_rssi = -80").is_err());
+        assert!(Config::parse_scan_filter("timeout_ms = 3000").is_err());
+        assert!(Config::parse_scan_filter("mode = passive").is_err());
+        assert!(Config::parse_scan_filter("[connect]\nauto_reconnect = yes").is_err());
+        assert!(Config::parse_scan_filter("[scan]\nfilter_mode = all").is_err());
+    }
+}
@@ -0,0 +1,337 @@
+use crate::backend;
+use crate::callbacks::invoke_connection_state;
+use crate::error::{Error, Result};
+use crate::last_device;
+use crate::queue::{self, Operation};
+use crate::scan::{format_address, resolve_device};
+use crate::state::STATE;
+use std::thread;
+use std::time::Duration;
+
+/// Lifecycle of the single device connection. `_getConnectionState` reports
+/// the discriminant; the connection state callback only sees 0 (Idle,
+/// Disconnecting), 1 (Connecting) and 2 (Connected, Discovering, Ready).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    Idle = 0,
+    Connecting = 1,
+    Connected = 2,
+    Discovering = 3,
+    Ready = 4,
+    Disconnecting = 5,
+}
+
+impl ConnectionState {
+    pub fn is_connected(self) -> bool {
+        matches!(
+            self,
+            ConnectionState::Connected | ConnectionState::Discovering | ConnectionState::Ready
+        )
+    }
+
+    fn callback_value(self) -> i32 {
+        match self {
+            ConnectionState::Idle | ConnectionState::Disconnecting => 0,
+            ConnectionState::Connecting => 1,
+            _ => 2,
+        }
+    }
+}
+
+/// Generation of the current connection. It changes on every connect,
+/// reconnect attempt and disconnect; backends tag their callbacks with the
+/// generation they were registered under so events from an earlier
+/// connection can be told apart and dropped.
+pub fn generation() -> u64 {
+    STATE.lock().unwrap().connection_generation
+}
+
+/// Enters `to` under a fresh generation, invalidating every pending
+/// completion, event and reconnect of the previous one.
+fn begin(to: ConnectionState) -> u64 {
+    let (generation, handler, notify) = {
+        let mut state = STATE.lock().unwrap();
+        state.connection_generation += 1;
+        let from = std::mem::replace(&mut state.connection_state, to);
+        log::debug!("Connection state {:?} -> {:?} (generation {})", from, to, state.connection_generation);
+        (
+            state.connection_generation,
+            state.connection_state_handler,
+            from.callback_value() != to.callback_value(),
+        )
+    };
+
+    if notify {
+        invoke_connection_state(handler, to.callback_value());
+    }
+    generation
+}
+
+/// Moves to `to` if `generation` is still current. Returns false, without
+/// touching the state, when the caller belongs to a superseded connection.
+pub fn transition(generation: u64, to: ConnectionState) -> bool {
+    let (handler, notify) = {
+        let mut state = STATE.lock().unwrap();
+        if state.connection_generation != generation {
+            return false;
+        }
+        let from = std::mem::replace(&mut state.connection_state, to);
+        if from != to {
+            log::debug!("Connection state {:?} -> {:?}", from, to);
+        }
+        (state.connection_state_handler, from.callback_value() != to.callback_value())
+    };
+
+    if notify {
+        invoke_connection_state(handler, to.callback_value());
+    }
+    true
+}
+
+pub fn connect(target: &str) -> Result<()> {
+    let (address, name) = match resolve_device(target) {
+        Some(device) => device,
+        None => {
+            disconnect();
+            return Err(Error::DeviceNotFound);
+        }
+    };
+
+    log::info!("Connecting to {} ({})", name, format_address(address));
+    // Release any previous device first; its handlers are tied to the
+    // generation that `begin` is about to retire.
+    backend::current().disconnect();
+    let generation = begin(ConnectionState::Connecting);
+
+    {
+        let mut state = STATE.lock().unwrap();
+        state.subscriptions.clear();
+        state.device_name = Some(name.clone());
+        state.device_address = address;
+    }
+
+    open_connection(generation, address)?;
+
+    log::info!("Connected to {}", format_address(address));
+    last_device::save(address, &name);
+    Ok(())
+}
+
+/// Connects the backend for `generation`. If the attempt was superseded while
+/// it ran (e.g. `_disconnect` during an in-flight connect), whatever the
+/// backend just opened is torn down again instead of being left half-connected.
+fn open_connection(generation: u64, address: u64) -> Result<()> {
+    let backend = backend::current();
+
+    if let Err(e) = backend.connect(address) {
+        if transition(generation, ConnectionState::Idle) {
+            backend.disconnect();
+        }
+        return Err(e);
+    }
+
+    if !transition(generation, ConnectionState::Connected) {
+        log::info!("Dropping stale connection to {}", format_address(address));
+        backend.disconnect();
+        return Err(Error::NotConnected);
+    }
+
+    Ok(())
+}
+
+pub fn disconnect() {
+    log::info!("Disconnecting");
+    let was_disconnected = STATE.lock().unwrap().connection_state.callback_value() == 0;
+    let generation = begin(ConnectionState::Disconnecting);
+
+    STATE.lock().unwrap().subscriptions.clear();
+    backend::current().disconnect();
+
+    transition(generation, ConnectionState::Idle);
+
+    // Leaving a connected state already reported 0; acknowledge the call
+    // anyway when there was nothing to disconnect.
+    if was_disconnected {
+        let handler = STATE.lock().unwrap().connection_state_handler;
+        invoke_connection_state(handler, 0);
+    }
+}
+
+/// Abandons the current connection's pending completions, events and
+/// reconnects without reporting a state change. Used when the plugin closes.
+pub fn invalidate() {
+    let mut state = STATE.lock().unwrap();
+    state.connection_generation += 1;
+    state.connection_state = ConnectionState::Idle;
+}
+
+pub fn handle_connection_status(generation: u64, status: i32) {
+    let current = {
+        let state = STATE.lock().unwrap();
+        (state.connection_generation == generation).then_some(state.connection_state)
+    };
+
+    let current = match current {
+        Some(current) => current,
+        None => {
+            log::debug!("Ignoring connection status {} from generation {}", status, generation);
+            return;
+        }
+    };
+
+    log::info!("Connection status changed to {} ({:?})", status, current);
+
+    match status {
+        0 if current.is_connected() => {
+            let (auto_reconnect, address, name) = {
+                let state = STATE.lock().unwrap();
+                (
+                    state.config.auto_reconnect,
+                    state.device_address,
+                    state.device_name.clone().unwrap_or_default(),
+                )
+            };
+
+            if !transition(generation, ConnectionState::Idle) {
+                return;
+            }
+
+            if auto_reconnect {
+                log::warn!("Lost connection to {}", format_address(address));
+                // Release the dead device along with its event registrations
+                // and characteristics, so the reconnect opens a fresh one and
+                // re-registers everything. Without auto-reconnect the device
+                // is kept, and the stack may bring the link back on its own.
+                backend::current().disconnect();
+                schedule_reconnect(generation, address, name, 1);
+            }
+        }
+        2 if current == ConnectionState::Idle => {
+            // The stack re-established the link on its own.
+            transition(generation, ConnectionState::Connected);
+        }
+        _ => {}
+    }
+}
+
+fn schedule_reconnect(generation: u64, address: u64, name: String, attempt: u32) {
+    let (max_attempts, delay) = {
+        let state = STATE.lock().unwrap();
+        let config = &state.config;
+        (config.reconnect_attempts, backoff_delay(config.reconnect_delay, config.reconnect_max_delay, attempt))
+    };
+
+    if attempt > max_attempts {
+        log::warn!("Giving up on {} after {} reconnect attempts", format_address(address), max_attempts);
+        return;
+    }
+
+    log::info!(
+        "Reconnecting to {} in {:?} (attempt {}/{})",
+        format_address(address),
+        delay,
+        attempt,
+        max_attempts
+    );
+
+    let spawned = thread::Builder::new()
+        .name("reconnect".to_string())
+        .spawn(move || {
+            thread::sleep(delay);
+
+            if self::generation() != generation {
+                return;
+            }
+
+            let op = Operation::Reconnect { address, name, attempt, generation };
+            if let Err(e) = queue::enqueue_internal(op) {
+                log::warn!("Could not queue reconnect: {}", e);
+            }
+        });
+
+    if let Err(e) = spawned {
+        log::warn!("Could not spawn reconnect timer: {}", e);
+    }
+}
+
+fn backoff_delay(initial: Duration, max: Duration, attempt: u32) -> Duration {
+    initial
+        .saturating_mul(1 << (attempt - 1).min(16))
+        .min(max)
+}
+
+/// One automatic reconnect attempt: reconnects to `address`, re-runs
+/// discovery and restores every subscription. A failed attempt schedules the
+/// next one with a doubled delay. `generation` is the connection that was
+/// lost; a connect, disconnect or earlier recovery since then cancels it.
+pub fn reconnect(address: u64, name: &str, attempt: u32, generation: u64) -> Result<()> {
+    {
+        let state = STATE.lock().unwrap();
+        if state.connection_generation != generation
+            || state.connection_state != ConnectionState::Idle
+        {
+            return Ok(());
+        }
+    }
+
+    log::info!("Reconnecting to {} (attempt {})", format_address(address), attempt);
+    backend::current().disconnect();
+    let generation = begin(ConnectionState::Connecting);
+
+    let result = open_connection(generation, address).and_then(|_| restore_session(generation));
+
+    match result {
+        Ok(_) => {
+            log::info!("Reconnected to {} ({})", name, format_address(address));
+            Ok(())
+        }
+        Err(e) => {
+            log::warn!("Reconnect attempt {} failed: {}", attempt, e);
+            if transition(generation, ConnectionState::Idle) {
+                backend::current().disconnect();
+                schedule_reconnect(generation, address, name.to_string(), attempt + 1);
+            }
+            Err(e)
+        }
+    }
+}
+
+fn restore_session(generation: u64) -> Result<()> {
+    let backend = backend::current();
+
+    if !transition(generation, ConnectionState::Discovering) {
+        return Err(Error::NotConnected);
+    }
+    backend.discover_services()?;
+
+    let subscriptions: Vec<String> = {
+        let state = STATE.lock().unwrap();
+        state.subscriptions.iter().cloned().collect()
+    };
+    for uuid in subscriptions {
+        backend.subscribe(&uuid)?;
+        log::info!("Restored subscription to {}", uuid);
+    }
+
+    if !transition(generation, ConnectionState::Ready) {
+        return Err(Error::NotConnected);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_the_limit() {
+        let initial = Duration::from_millis(500);
+        let max = Duration::from_secs(8);
+
+        assert_eq!(backoff_delay(initial, max, 1), Duration::from_millis(500));
+        assert_eq!(backoff_delay(initial, max, 2), Duration::from_secs(1));
+        assert_eq!(backoff_delay(initial, max, 4), Duration::from_secs(4));
+        assert_eq!(backoff_delay(initial, max, 6), max);
+        assert_eq!(backoff_delay(initial, max, 100), max);
+    }
+}
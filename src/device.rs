@@ -1,66 +1,42 @@
-use crate::callbacks::invoke_connection_state;
+use crate::async_op::AsyncOperationExt;
+use crate::error::Result;
 use crate::state::STATE;
-use crate::watcher::get_address_for_name;
 use windows::Devices::Bluetooth::BluetoothLEDevice;
 use windows::Devices::Bluetooth::BluetoothConnectionStatus;
+use windows::Devices::Bluetooth::GenericAttributeProfile::GattSession;
+use std::time::Instant;
 
-pub fn connect_by_name(name: &str) -> Result<(), windows::core::Error> {
-    let address = match get_address_for_name(name) {
-        Some(addr) => addr,
-        None => {
-            let state = STATE.lock().unwrap();
-            let mut found_addr: Option<u64> = None;
-            for (stored_name, &addr) in state.discovered_devices.iter() {
-                if name.starts_with(stored_name) {
-                    found_addr = Some(addr);
-                    break;
-                }
-            }
-            drop(state);
-            match found_addr {
-                Some(addr) => addr,
-                None => {
-                    return Err(windows::core::Error::new(
-                        windows::core::HRESULT(-1),
-                        "Device not found in scan results",
-                    ));
-                }
-            }
-        }
-    };
-
-    notify_connection_state(1);
+pub fn connect(address: u64) -> Result<()> {
+    let timeout = STATE.lock().unwrap().config.connect_timeout;
+    let deadline = Instant::now() + timeout;
 
     let async_op = BluetoothLEDevice::FromBluetoothAddressAsync(address)?;
-    let device = async_op.get()?;
+    let device = async_op.get_timeout(timeout)?;
+
+    let session = device
+        .BluetoothDeviceId()
+        .and_then(|id| GattSession::FromDeviceIdAsync(&id))
+        .map_err(Into::into)
+        .and_then(|op| op.get_timeout(deadline.saturating_duration_since(Instant::now())))
+        .ok();
 
     {
         let mut state = STATE.lock().unwrap();
         state.device = Some(device.clone());
-        state.device_name = Some(name.to_string());
-        state.device_address = address;
-        state.is_connected = true;
+        state.gatt_session = session;
     }
 
     setup_connection_status_handler(&device)?;
 
-    notify_connection_state(2);
-
     Ok(())
 }
 
-fn setup_connection_status_handler(device: &BluetoothLEDevice) -> Result<(), windows::core::Error> {
+fn setup_connection_status_handler(device: &BluetoothLEDevice) -> Result<()> {
     use windows::Foundation::TypedEventHandler;
 
-    {
-        let state = STATE.lock().unwrap();
-        if state.connection_status_handler.is_some() {
-            return Ok(());
-        }
-    }
-
+    let generation = crate::connection::generation();
     let handler = TypedEventHandler::new(
-        |_sender: &Option<BluetoothLEDevice>, _args: &Option<windows::core::IInspectable>| {
+        move |_sender: &Option<BluetoothLEDevice>, _args: &Option<windows::core::IInspectable>| {
             let state = STATE.lock().unwrap();
             if let Some(ref dev) = state.device {
                 if let Ok(status) = dev.ConnectionStatus() {
@@ -70,43 +46,50 @@ fn setup_connection_status_handler(device: &BluetoothLEDevice) -> Result<(), win
                         _ => 1,
                     };
                     drop(state);
-                    notify_connection_state(state_val);
+                    crate::connection::handle_connection_status(generation, state_val);
                 }
             }
             Ok(())
         },
     );
 
-    device.ConnectionStatusChanged(&handler)?;
+    let token = device.ConnectionStatusChanged(&handler)?;
 
     {
         let mut state = STATE.lock().unwrap();
-        state.connection_status_handler = Some(send_wrapper::SendWrapper::new(handler));
+        state.connection_status_token = Some(token);
     }
 
     Ok(())
 }
 
+/// Unregisters every WinRT event handler of the connection before dropping
+/// it; the handlers are removed outside the state lock because they take it.
 pub fn disconnect() {
-    let handler;
-    {
-        let mut state = STATE.lock().unwrap();
-        handler = state.connection_state_handler;
-        state.device = None;
-        state.is_connected = false;
+    let (device, connection_status_token, notifications) = {
+        let mut guard = STATE.lock().unwrap();
+        let state = &mut *guard;
+        let notifications: Vec<_> = state
+            .notification_tokens
+            .drain()
+            .filter_map(|(uuid, token)| {
+                state.characteristics.get(&uuid).cloned().map(|c| (c, token))
+            })
+            .collect();
+
+        let device = state.device.take();
+        let token = state.connection_status_token.take();
+        state.gatt_session = None;
         state.services.clear();
         state.characteristics.clear();
-        state.notification_handlers.clear();
-        state.connection_status_handler = None;
-    }
+        (device, token, notifications)
+    };
 
-    invoke_connection_state(handler, 0);
-}
+    for (characteristic, token) in notifications {
+        let _ = characteristic.RemoveValueChanged(token);
+    }
 
-fn notify_connection_state(state: i32) {
-    let handler = {
-        let s = STATE.lock().unwrap();
-        s.connection_state_handler
-    };
-    invoke_connection_state(handler, state);
+    if let (Some(device), Some(token)) = (device, connection_status_token) {
+        let _ = device.RemoveConnectionStatusChanged(token);
+    }
 }
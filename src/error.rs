@@ -0,0 +1,91 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{LazyLock, Mutex};
+use std::thread::{self, ThreadId};
+
+static LAST_ERRORS: LazyLock<Mutex<HashMap<ThreadId, Error>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    NotInitialized,
+    NotConnected,
+    DeviceNotFound,
+    CharacteristicNotFound,
+    QueueFull,
+    GattStatus(i32),
+    Timeout,
+    InvalidArgument,
+    #[cfg_attr(not(windows), allow(dead_code))]
+    Backend { code: i32, message: String },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// Stable code reported by `_getLastError`; 0 means no error.
+    pub fn code(&self) -> i32 {
+        match self {
+            Error::NotInitialized => 1,
+            Error::NotConnected => 2,
+            Error::DeviceNotFound => 3,
+            Error::CharacteristicNotFound => 4,
+            Error::QueueFull => 5,
+            Error::GattStatus(_) => 6,
+            Error::Timeout => 7,
+            Error::InvalidArgument => 8,
+            Error::Backend { .. } => 9,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NotInitialized => write!(f, "Plugin not initialized"),
+            Error::NotConnected => write!(f, "Not connected"),
+            Error::DeviceNotFound => write!(f, "Device not found in scan results"),
+            Error::CharacteristicNotFound => write!(f, "Characteristic not found"),
+            Error::QueueFull => write!(f, "GATT operation queue is full"),
+            Error::GattStatus(status) => {
+                let name = match status {
+                    1 => "unreachable",
+                    2 => "protocol error",
+                    3 => "access denied",
+                    _ => "unknown status",
+                };
+                write!(f, "GATT operation failed: {} ({})", name, status)
+            }
+            Error::Timeout => write!(f, "Operation timed out"),
+            Error::InvalidArgument => write!(f, "Invalid argument"),
+            Error::Backend { code, message } => write!(f, "{} (0x{:08X})", message, code),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[cfg(windows)]
+impl From<windows::core::Error> for Error {
+    fn from(e: windows::core::Error) -> Self {
+        Error::Backend {
+            code: e.code().0,
+            message: e.message(),
+        }
+    }
+}
+
+/// Records `e` as the last error of the calling thread.
+pub fn set_last_error(e: Error) {
+    set_last_error_for(thread::current().id(), e);
+}
+
+/// Records `e` as the last error of `thread`, so failures of queued
+/// operations are visible to the thread that submitted them.
+pub fn set_last_error_for(thread: ThreadId, e: Error) {
+    LAST_ERRORS.lock().unwrap().insert(thread, e);
+}
+
+pub fn last_error() -> Option<Error> {
+    LAST_ERRORS.lock().unwrap().get(&thread::current().id()).cloned()
+}
@@ -1,99 +1,101 @@
-use crate::callbacks::{invoke_service_discovered, invoke_value_changed};
+use crate::async_op::AsyncOperationExt;
+use crate::backend::{Service, WriteType};
+use crate::error::{Error, Result};
 use crate::state::STATE;
 use windows::Devices::Bluetooth::GenericAttributeProfile::{
     GattCharacteristic, GattClientCharacteristicConfigurationDescriptorValue,
-    GattCommunicationStatus, GattDeviceService, GattValueChangedEventArgs,
+    GattCommunicationStatus, GattDeviceService, GattValueChangedEventArgs, GattWriteOption,
 };
 use windows::Foundation::TypedEventHandler;
 use windows::Storage::Streams::DataReader;
 use windows::Security::Cryptography::CryptographicBuffer;
+use windows::core::GUID;
+use std::time::Duration;
 
-pub fn discover_services() -> Result<(), windows::core::Error> {
+fn gatt_timeout() -> Duration {
+    STATE.lock().unwrap().config.gatt_timeout
+}
+
+pub fn discover_services() -> Result<Vec<Service>> {
     let device = {
         let state = STATE.lock().unwrap();
         state.device.clone()
     };
 
-    let device = match device {
-        Some(d) => d,
-        None => {
-            return Err(windows::core::Error::new(windows::core::HRESULT(-1), "Not connected"));
-        }
-    };
+    let device = device.ok_or(Error::NotConnected)?;
 
     let async_op = device.GetGattServicesAsync()?;
-    let result = async_op.get()?;
+    let result = async_op.get_timeout(gatt_timeout())?;
 
-    if result.Status()? != GattCommunicationStatus::Success {
-        return Err(windows::core::Error::new(
-            windows::core::HRESULT(-1),
-            "Failed to get services",
-        ));
+    let status = result.Status()?;
+    if status != GattCommunicationStatus::Success {
+        return Err(Error::GattStatus(status.0));
     }
 
-    let services = result.Services()?;
-    let service_handler;
-    {
-        let state = STATE.lock().unwrap();
-        service_handler = state.service_discovered_handler;
-    }
+    let mut discovered = Vec::new();
 
-    for service in services {
-        let guid = service.Uuid()?;
-        let uuid_clean = format!("{:08x}-{:04x}-{:04x}-{:02x}{:02x}-{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}",
-            guid.data1, guid.data2, guid.data3,
-            guid.data4[0], guid.data4[1], guid.data4[2], guid.data4[3],
-            guid.data4[4], guid.data4[5], guid.data4[6], guid.data4[7]);
+    for service in result.Services()? {
+        let uuid_clean = format_uuid(&service.Uuid()?);
 
         {
             let mut state = STATE.lock().unwrap();
             state.services.insert(uuid_clean.clone(), service.clone());
         }
 
-        invoke_service_discovered(service_handler, 0, &uuid_clean);
-
-        discover_characteristics(&service, service_handler)?;
+        let characteristics = discover_characteristics(&service)?;
+        discovered.push(Service { uuid: uuid_clean, characteristics });
     }
 
-    Ok(())
+    Ok(discovered)
 }
 
-fn discover_characteristics(
-    service: &GattDeviceService,
-    handler: crate::callbacks::ServiceDiscoveredHandler,
-) -> Result<(), windows::core::Error> {
+fn discover_characteristics(service: &GattDeviceService) -> Result<Vec<String>> {
     let async_op = service.GetCharacteristicsAsync()?;
-    let result = async_op.get()?;
+    let result = async_op.get_timeout(gatt_timeout())?;
 
     if result.Status()? != GattCommunicationStatus::Success {
-        return Ok(());
+        return Ok(Vec::new());
     }
 
-    let characteristics = result.Characteristics()?;
+    let mut discovered = Vec::new();
 
-    for characteristic in characteristics {
-        let guid = characteristic.Uuid()?;
-        let uuid_clean = format!("{:08x}-{:04x}-{:04x}-{:02x}{:02x}-{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}",
-            guid.data1, guid.data2, guid.data3,
-            guid.data4[0], guid.data4[1], guid.data4[2], guid.data4[3],
-            guid.data4[4], guid.data4[5], guid.data4[6], guid.data4[7]);
+    for characteristic in result.Characteristics()? {
+        let uuid_clean = format_uuid(&characteristic.Uuid()?);
 
         {
             let mut state = STATE.lock().unwrap();
             state.characteristics.insert(uuid_clean.clone(), characteristic);
         }
 
-        invoke_service_discovered(handler, 1, &uuid_clean);
+        discovered.push(uuid_clean);
+    }
 
-        if uuid_clean.to_lowercase() == "86602102-6b7e-439a-bdd1-489a3213e9bb" {
-            let _ = enable_notification(&uuid_clean);
-        }
+    Ok(discovered)
+}
+
+pub fn format_uuid(guid: &GUID) -> String {
+    format!("{:08x}-{:04x}-{:04x}-{:02x}{:02x}-{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}",
+        guid.data1, guid.data2, guid.data3,
+        guid.data4[0], guid.data4[1], guid.data4[2], guid.data4[3],
+        guid.data4[4], guid.data4[5], guid.data4[6], guid.data4[7])
+}
+
+const DEFAULT_ATT_MTU: u16 = 23;
+
+pub fn max_pdu_size() -> Result<u16> {
+    let state = STATE.lock().unwrap();
+
+    if state.device.is_none() {
+        return Err(Error::NotConnected);
     }
 
-    Ok(())
+    match state.gatt_session {
+        Some(ref session) => Ok(session.MaxPduSize()?),
+        None => Ok(DEFAULT_ATT_MTU),
+    }
 }
 
-pub fn read_characteristic(uuid: &str) -> Result<Vec<u8>, windows::core::Error> {
+pub fn read_characteristic(uuid: &str) -> Result<Vec<u8>> {
     let uuid_lower = uuid.to_lowercase();
 
     let characteristic = {
@@ -101,18 +103,14 @@ pub fn read_characteristic(uuid: &str) -> Result<Vec<u8>, windows::core::Error>
         state.characteristics.get(&uuid_lower).cloned()
     };
 
-    let characteristic = characteristic.ok_or_else(|| {
-        windows::core::Error::new(windows::core::HRESULT(-1), "Characteristic not found")
-    })?;
+    let characteristic = characteristic.ok_or(Error::CharacteristicNotFound)?;
 
     let async_op = characteristic.ReadValueAsync()?;
-    let result = async_op.get()?;
+    let result = async_op.get_timeout(gatt_timeout())?;
 
-    if result.Status()? != GattCommunicationStatus::Success {
-        return Err(windows::core::Error::new(
-            windows::core::HRESULT(-1),
-            "Read failed",
-        ));
+    let status = result.Status()?;
+    if status != GattCommunicationStatus::Success {
+        return Err(Error::GattStatus(status.0));
     }
 
     let buffer = result.Value()?;
@@ -125,7 +123,7 @@ pub fn read_characteristic(uuid: &str) -> Result<Vec<u8>, windows::core::Error>
     Ok(data)
 }
 
-pub fn write_characteristic(uuid: &str, data: &[u8]) -> Result<(), windows::core::Error> {
+pub fn write_characteristic(uuid: &str, data: &[u8], write_type: WriteType) -> Result<()> {
     let uuid_lower = uuid.to_lowercase();
 
     let characteristic = {
@@ -133,72 +131,73 @@ pub fn write_characteristic(uuid: &str, data: &[u8]) -> Result<(), windows::core
         state.characteristics.get(&uuid_lower).cloned()
     };
 
-    let characteristic = characteristic.ok_or_else(|| {
-        windows::core::Error::new(windows::core::HRESULT(-1), "Characteristic not found")
-    })?;
+    let characteristic = characteristic.ok_or(Error::CharacteristicNotFound)?;
+
+    let properties = characteristic.CharacteristicProperties()?;
+    let write_option = match write_type.resolve(properties.0) {
+        WriteType::WithoutResponse => GattWriteOption::WriteWithoutResponse,
+        _ => GattWriteOption::WriteWithResponse,
+    };
 
     let buffer = CryptographicBuffer::CreateFromByteArray(data)?;
 
-    match characteristic.WriteValueAsync(&buffer) {
-        Ok(_op) => {}
-        Err(e) => return Err(e),
-    };
+    let async_op = characteristic.WriteValueWithOptionAsync(&buffer, write_option)?;
+    let status = async_op.get_timeout(gatt_timeout())?;
+
+    if status != GattCommunicationStatus::Success {
+        return Err(Error::GattStatus(status.0));
+    }
 
     Ok(())
 }
 
-pub fn enable_notification(uuid: &str) -> Result<(), windows::core::Error> {
+pub fn enable_notification(uuid: &str) -> Result<()> {
     let uuid_lower = uuid.to_lowercase();
 
-    {
-        let state = STATE.lock().unwrap();
-        if state.notification_handlers.contains_key(&uuid_lower) {
-            return Ok(());
-        }
-    }
-
     let characteristic = {
         let state = STATE.lock().unwrap();
         state.characteristics.get(&uuid_lower).cloned()
     };
 
-    let characteristic = characteristic.ok_or_else(|| {
-        windows::core::Error::new(windows::core::HRESULT(-1), "Characteristic not found")
-    })?;
+    let characteristic = characteristic.ok_or(Error::CharacteristicNotFound)?;
 
     let cccd_value = GattClientCharacteristicConfigurationDescriptorValue::Notify;
     let async_op = characteristic.WriteClientCharacteristicConfigurationDescriptorAsync(cccd_value)?;
-    let status = async_op.get()?;
+    let status = async_op.get_timeout(gatt_timeout())?;
 
     if status != GattCommunicationStatus::Success {
-        return Err(windows::core::Error::new(
-            windows::core::HRESULT(-1),
-            "Enable notification failed",
-        ));
+        return Err(Error::GattStatus(status.0));
     }
 
     let uuid_clone = uuid_lower.clone();
+    let generation = crate::connection::generation();
     let handler = TypedEventHandler::new(
         move |_sender: &Option<GattCharacteristic>,
               args: &Option<GattValueChangedEventArgs>| {
             if let Some(args) = args {
-                handle_value_changed(&uuid_clone, args);
+                handle_value_changed(generation, &uuid_clone, args);
             }
             Ok(())
         },
     );
 
-    characteristic.ValueChanged(&handler)?;
+    let token = characteristic.ValueChanged(&handler)?;
 
-    {
+    // A repeated subscribe replaces the earlier handler, which carries the
+    // generation it was registered under, rather than keeping both.
+    let previous = {
         let mut state = STATE.lock().unwrap();
-        state.notification_handlers.insert(uuid_lower, send_wrapper::SendWrapper::new(handler));
+        state.notification_tokens.insert(uuid_lower, token)
+    };
+
+    if let Some(previous) = previous {
+        let _ = characteristic.RemoveValueChanged(previous);
     }
 
     Ok(())
 }
 
-pub fn disable_notification(uuid: &str) -> Result<(), windows::core::Error> {
+pub fn disable_notification(uuid: &str) -> Result<()> {
     let uuid_lower = uuid.to_lowercase();
 
     let characteristic = {
@@ -206,30 +205,29 @@ pub fn disable_notification(uuid: &str) -> Result<(), windows::core::Error> {
         state.characteristics.get(&uuid_lower).cloned()
     };
 
-    let characteristic = characteristic.ok_or_else(|| {
-        windows::core::Error::new(windows::core::HRESULT(-1), "Characteristic not found")
-    })?;
+    let characteristic = characteristic.ok_or(Error::CharacteristicNotFound)?;
 
     let cccd_value = GattClientCharacteristicConfigurationDescriptorValue::None;
     let async_op = characteristic.WriteClientCharacteristicConfigurationDescriptorAsync(cccd_value)?;
-    let status = async_op.get()?;
+    let status = async_op.get_timeout(gatt_timeout())?;
 
     if status != GattCommunicationStatus::Success {
-        return Err(windows::core::Error::new(
-            windows::core::HRESULT(-1),
-            "Disable notification failed",
-        ));
+        return Err(Error::GattStatus(status.0));
     }
 
-    {
+    let token = {
         let mut state = STATE.lock().unwrap();
-        state.notification_handlers.remove(&uuid_lower);
+        state.notification_tokens.remove(&uuid_lower)
+    };
+
+    if let Some(token) = token {
+        characteristic.RemoveValueChanged(token)?;
     }
 
     Ok(())
 }
 
-fn handle_value_changed(uuid: &str, args: &GattValueChangedEventArgs) {
+fn handle_value_changed(generation: u64, uuid: &str, args: &GattValueChangedEventArgs) {
     let buffer = match args.CharacteristicValue() {
         Ok(b) => b,
         Err(_) => return,
@@ -250,10 +248,5 @@ fn handle_value_changed(uuid: &str, args: &GattValueChangedEventArgs) {
         return;
     }
 
-    let handler = {
-        let state = STATE.lock().unwrap();
-        state.value_changed_handler
-    };
-
-    invoke_value_changed(handler, uuid, &data);
+    crate::client::handle_value_changed(generation, uuid, &data);
 }
@@ -0,0 +1,116 @@
+use crate::config;
+use crate::connection::ConnectionState;
+use crate::queue::{self, Operation};
+use crate::scan::{format_address, parse_address};
+use crate::state::STATE;
+use std::path::PathBuf;
+
+const STATE_FILE_NAME: &str = "squaregolf-plugin.state";
+
+/// When to connect to the remembered device without the player picking it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AutoConnect {
+    Off,
+    Open,
+    Scan,
+}
+
+impl AutoConnect {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "off" | "false" | "no" => Some(AutoConnect::Off),
+            "open" => Some(AutoConnect::Open),
+            "scan" => Some(AutoConnect::Scan),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LastDevice {
+    pub address: u64,
+    pub name: String,
+}
+
+/// The state file lives next to `plugin.dll`, or in the temp directory when
+/// the game folder is not writable.
+fn candidates() -> impl Iterator<Item = PathBuf> {
+    config::data_dirs().into_iter().map(|dir| dir.join(STATE_FILE_NAME))
+}
+
+pub fn load() -> Option<LastDevice> {
+    candidates()
+        .filter_map(|path| std::fs::read_to_string(path).ok())
+        .find_map(|text| parse(&text))
+}
+
+fn parse(text: &str) -> Option<LastDevice> {
+    let mut address = None;
+    let mut name = String::new();
+
+    for line in text.lines() {
+        match line.split_once('=') {
+            Some((key, value)) if key.trim() == "address" => address = parse_address(value),
+            Some((key, value)) if key.trim() == "name" => name = value.trim().to_string(),
+            _ => {}
+        }
+    }
+
+    address.map(|address| LastDevice { address, name })
+}
+
+/// Records the device just connected to. A connect by address does not know
+/// the name, so the one already on file for that address is kept.
+pub fn save(address: u64, name: &str) {
+    let name = match load() {
+        Some(last) if name.is_empty() && last.address == address => last.name,
+        _ => name.to_string(),
+    };
+    let text = format!("address = {}\nname = {}\n", format_address(address), name);
+
+    match candidates().find(|path| std::fs::write(path, &text).is_ok()) {
+        Some(path) => log::debug!("Remembered {} in {}", format_address(address), path.display()),
+        None => log::warn!("Could not save the last connected device"),
+    }
+}
+
+/// Queues a direct connect to the remembered device when `trigger` is the
+/// configured auto-connect point. Tried at most once per `_open`, and only
+/// while nothing is connected.
+pub fn auto_connect(trigger: AutoConnect) {
+    {
+        let mut state = STATE.lock().unwrap();
+        if state.config.auto_connect_last != trigger
+            || state.connection_state != ConnectionState::Idle
+            || !std::mem::take(&mut state.auto_connect_pending)
+        {
+            return;
+        }
+    }
+
+    let Some(last) = load() else {
+        log::info!("No remembered device to auto-connect to");
+        return;
+    };
+
+    log::info!("Auto-connecting to remembered {} ({})", last.name, format_address(last.address));
+    if let Err(e) = queue::enqueue_internal(Operation::Connect(format_address(last.address))) {
+        log::warn!("Could not queue auto-connect: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_state_file() {
+        let last = parse("address = C0FFEE000001\nname = SquareGolf 2\n").unwrap();
+        assert_eq!(last.address, 0xC0FFEE000001);
+        assert_eq!(last.name, "SquareGolf 2");
+
+        assert!(parse("name = SquareGolf\n").is_none());
+        assert_eq!(AutoConnect::parse("Scan"), Some(AutoConnect::Scan));
+        assert_eq!(AutoConnect::parse("sometimes"), None);
+    }
+}
@@ -1,9 +1,24 @@
 #![allow(non_snake_case)]
 #![allow(clippy::missing_safety_doc)]
 
+mod backend;
 mod callbacks;
+mod client;
+mod config;
+mod connection;
+mod error;
+mod last_device;
+mod logging;
+mod pattern;
+mod queue;
+mod scan;
 mod state;
 
+#[cfg(test)]
+mod tests;
+
+#[cfg(windows)]
+mod async_op;
 #[cfg(windows)]
 mod watcher;
 #[cfg(windows)]
@@ -11,23 +26,25 @@ mod device;
 #[cfg(windows)]
 mod gatt;
 
+use backend::WriteType;
 use callbacks::*;
+use error::Error;
+use queue::Operation;
 use state::STATE;
 
 type WideChar = u16;
 
+fn fail(context: &str, e: Error) -> i32 {
+    log::warn!("{} failed: {}", context, e);
+    error::set_last_error(e);
+    -1
+}
+
 #[no_mangle]
 pub extern "C" fn _open() -> i32 {
-    #[cfg(windows)]
-    {
-        match state::initialize() {
-            Ok(_) => 0,
-            Err(_) => -1,
-        }
-    }
-    #[cfg(not(windows))]
-    {
-        -1
+    match state::initialize() {
+        Ok(_) => 0,
+        Err(e) => fail("_open", e),
     }
 }
 
@@ -44,26 +61,65 @@ pub extern "C" fn _release() -> i32 {
 }
 
 #[no_mangle]
-pub extern "C" fn _startScan(_enable_filter: i32) -> i32 {
-    #[cfg(windows)]
-    {
-        match watcher::start_scan() {
-            Ok(_) => 0,
-            Err(_) => -1,
+pub extern "C" fn _startScan(enable_filter: i32) -> i32 {
+    let (timeout, auto_connect) = {
+        let state = STATE.lock().unwrap();
+        (state.config.scan_timeout, state.config.scan_auto_connect)
+    };
+
+    match scan::start_scan(enable_filter != 0, timeout, auto_connect) {
+        Ok(_) => {
+            last_device::auto_connect(last_device::AutoConnect::Scan);
+            0
         }
+        Err(e) => fail("_startScan", e),
     }
-    #[cfg(not(windows))]
-    {
-        -1
+}
+
+/// Scans for `timeout_ms` (0 or less: until `_stopScan`), then stops and, if
+/// `auto_connect` is non-zero, connects to the strongest matching device.
+#[no_mangle]
+pub extern "C" fn _startScanWithTimeout(enable_filter: i32, timeout_ms: i32, auto_connect: i32) -> i32 {
+    let timeout = (timeout_ms > 0).then(|| std::time::Duration::from_millis(timeout_ms as u64));
+
+    match scan::start_scan(enable_filter != 0, timeout, auto_connect != 0) {
+        Ok(_) => {
+            last_device::auto_connect(last_device::AutoConnect::Scan);
+            0
+        }
+        Err(e) => fail("_startScanWithTimeout", e),
     }
 }
 
+/// Replaces the criteria `_startScan(1)` filters on. `filter` holds `[scan]`
+/// keys of plugin.ini, one per line (`name_filters`, `name_patterns`,
+/// `service_uuids`, `manufacturer_filters`, `filter_mode`); an empty or null
+/// filter lets every device through. A line that does not parse fails the
+/// call and keeps the previous filter.
 #[no_mangle]
-pub extern "C" fn _stopScan() -> i32 {
-    #[cfg(windows)]
-    {
-        watcher::stop_scan();
+pub unsafe extern "C" fn _setScanFilter(filter: *const WideChar, filter_len: i32) -> i32 {
+    let spec = if filter.is_null() || filter_len <= 0 {
+        String::new()
+    } else {
+        unsafe {
+            let slice = std::slice::from_raw_parts(filter, filter_len as usize);
+            let actual_len = slice.iter().position(|&c| c == 0).unwrap_or(slice.len());
+            String::from_utf16_lossy(&slice[..actual_len])
+        }
+    };
+
+    match config::Config::parse_scan_filter(&spec) {
+        Ok(config) => {
+            scan::set_filter(scan::ScanFilter::from_config(&config));
+            0
+        }
+        Err(e) => fail("_setScanFilter", e),
     }
+}
+
+#[no_mangle]
+pub extern "C" fn _stopScan() -> i32 {
+    scan::stop_scan();
     0
 }
 
@@ -77,68 +133,55 @@ pub extern "C" fn _clearScan() -> i32 {
 #[no_mangle]
 pub unsafe extern "C" fn _connect(addr: *const WideChar, addr_len: i32) -> i32 {
     if addr.is_null() || addr_len <= 0 {
-        return -1;
+        return fail("_connect", Error::InvalidArgument);
     }
 
-    let name = unsafe {
+    let target = unsafe {
         let slice = std::slice::from_raw_parts(addr, addr_len as usize);
         let actual_len = slice.iter().position(|&c| c == 0).unwrap_or(slice.len());
         String::from_utf16_lossy(&slice[..actual_len])
     };
 
-    #[cfg(windows)]
-    {
-        std::thread::spawn(move || {
-            match device::connect_by_name(&name) {
-                Ok(_) => {},
-                Err(_) => {
-                    device::disconnect();
-                }
-            }
-        });
-        0
-    }
-    #[cfg(not(windows))]
-    {
-        let _ = name;
-        -1
+    match queue::enqueue(Operation::Connect(target)) {
+        Ok(_) => 0,
+        Err(e) => fail("_connect", e),
     }
 }
 
 #[no_mangle]
 pub extern "C" fn _disconnect() -> i32 {
-    #[cfg(windows)]
-    {
-        device::disconnect();
-    }
+    queue::clear();
+    connection::disconnect();
     0
 }
 
 #[no_mangle]
 pub extern "C" fn _discoverServices() -> i32 {
-    use std::sync::atomic::Ordering;
-    use state::DISCOVERY_IN_PROGRESS;
+    use state::{DISCOVERY_DONE, DISCOVERY_IN_PROGRESS};
 
-    let was_already_running = DISCOVERY_IN_PROGRESS.swap(true, Ordering::SeqCst);
+    let timeout = STATE.lock().unwrap().config.discovery_timeout;
 
-    if was_already_running {
-        while DISCOVERY_IN_PROGRESS.load(Ordering::SeqCst) {
-            std::thread::sleep(std::time::Duration::from_millis(10));
+    {
+        let mut running = DISCOVERY_IN_PROGRESS.lock().unwrap();
+        if *running {
+            let (_running, wait) = DISCOVERY_DONE
+                .wait_timeout_while(running, timeout, |running| *running)
+                .unwrap();
+            if wait.timed_out() {
+                return fail("_discoverServices", Error::Timeout);
+            }
+            return 0;
         }
-        return 0;
+        *running = true;
     }
 
-    #[cfg(windows)]
-    let result = {
-        match gatt::discover_services() {
-            Ok(_) => 0,
-            Err(_) => -1,
-        }
+    let result = match client::discover_services() {
+        Ok(_) => 0,
+        Err(e) => fail("_discoverServices", e),
     };
-    #[cfg(not(windows))]
-    let result = -1;
 
-    DISCOVERY_IN_PROGRESS.store(false, Ordering::SeqCst);
+    *DISCOVERY_IN_PROGRESS.lock().unwrap() = false;
+    DISCOVERY_DONE.notify_all();
     result
 }
 
@@ -150,13 +193,13 @@ pub unsafe extern "C" fn _read(
     out_len: *mut i32,
 ) -> i32 {
     if uuid.is_null() || uuid_len <= 0 || out_len.is_null() {
-        return -1;
+        return fail("_read", Error::InvalidArgument);
     }
 
     let initial_buf_size = unsafe { *out_len } as usize;
 
     if buffer.is_null() && initial_buf_size > 0 {
-        return -1;
+        return fail("_read", Error::InvalidArgument);
     }
 
     let uuid_str = unsafe {
@@ -164,75 +207,67 @@ pub unsafe extern "C" fn _read(
         String::from_utf16_lossy(slice)
     };
 
-    #[cfg(windows)]
-    {
-        let uuid_lower = uuid_str.to_lowercase();
-
-        if initial_buf_size == 0 {
-            match gatt::read_characteristic(&uuid_lower) {
-                Ok(data) => {
-                    let data_len = data.len();
-                    {
-                        let mut state = STATE.lock().unwrap();
-                        state.read_cache.insert(uuid_lower.clone(), data);
-                        state.read_first_pass_buffer.insert(uuid_lower, buffer as usize);
-                    }
-                    unsafe { *out_len = data_len as i32; }
-                    return data_len as i32;
-                }
-                Err(_) => return -1,
-            }
-        }
+    let uuid_lower = uuid_str.to_lowercase();
 
-        let (cached_data, first_pass_buffer) = {
-            let mut state = STATE.lock().unwrap();
-            let data = state.read_cache.remove(&uuid_lower);
-            let first_buf = state.read_first_pass_buffer.remove(&uuid_lower);
-            (data, first_buf)
-        };
-
-        let data = match cached_data {
-            Some(d) => d,
-            None => {
-                match gatt::read_characteristic(&uuid_lower) {
-                    Ok(d) => d,
-                    Err(_) => return -1,
+    if initial_buf_size == 0 {
+        match client::read(&uuid_lower) {
+            Ok(data) => {
+                let data_len = data.len();
+                {
+                    let mut state = STATE.lock().unwrap();
+                    state.read_cache.insert(uuid_lower.clone(), data);
+                    state.read_first_pass_buffer.insert(uuid_lower, buffer as usize);
                 }
+                unsafe { *out_len = data_len as i32; }
+                return data_len as i32;
             }
-        };
-
-        let copy_len = data.len().min(initial_buf_size);
+            Err(e) => return fail("_read", e),
+        }
+    }
 
-        let is_byte_ptr_ptr = first_pass_buffer.is_some()
-            && first_pass_buffer.unwrap() == buffer as usize
-            && !buffer.is_null();
+    let (cached_data, first_pass_buffer) = {
+        let mut state = STATE.lock().unwrap();
+        let data = state.read_cache.remove(&uuid_lower);
+        let first_buf = state.read_first_pass_buffer.remove(&uuid_lower);
+        (data, first_buf)
+    };
 
-        let actual_buffer = if is_byte_ptr_ptr {
-            let inner = unsafe { *(buffer as *mut *mut u8) };
-            if inner.is_null() {
-                return -1;
+    let data = match cached_data {
+        Some(d) => d,
+        None => {
+            match client::read(&uuid_lower) {
+                Ok(d) => d,
+                Err(e) => return fail("_read", e),
             }
-            inner
-        } else {
-            buffer
-        };
-
-        if actual_buffer.is_null() {
-            return -1;
         }
+    };
 
-        unsafe {
-            std::ptr::copy_nonoverlapping(data.as_ptr(), actual_buffer, copy_len);
-            *out_len = copy_len as i32;
+    let copy_len = data.len().min(initial_buf_size);
+
+    let is_byte_ptr_ptr = first_pass_buffer.is_some()
+        && first_pass_buffer.unwrap() == buffer as usize
+        && !buffer.is_null();
+
+    let actual_buffer = if is_byte_ptr_ptr {
+        let inner = unsafe { *(buffer as *mut *mut u8) };
+        if inner.is_null() {
+            return fail("_read", Error::InvalidArgument);
         }
+        inner
+    } else {
+        buffer
+    };
 
-        copy_len as i32
+    if actual_buffer.is_null() {
+        return fail("_read", Error::InvalidArgument);
     }
-    #[cfg(not(windows))]
-    {
-        let _ = uuid_str;
-        -1
+
+    unsafe {
+        std::ptr::copy_nonoverlapping(data.as_ptr(), actual_buffer, copy_len);
+        *out_len = copy_len as i32;
     }
+
+    copy_len as i32
 }
 
 #[no_mangle]
@@ -241,11 +276,29 @@ pub unsafe extern "C" fn _write(
     uuid_len: i32,
     data: *const u8,
     data_len: i32,
+) -> i32 {
+    _writeWithType(uuid, uuid_len, data, data_len, 0)
+}
+
+/// `write_type`: 0 = pick from the characteristic's properties,
+/// 1 = write with response, 2 = write without response.
+#[no_mangle]
+pub unsafe extern "C" fn _writeWithType(
+    uuid: *const WideChar,
+    uuid_len: i32,
+    data: *const u8,
+    data_len: i32,
+    write_type: i32,
 ) -> i32 {
     if uuid.is_null() || uuid_len <= 0 || data.is_null() || data_len <= 0 {
-        return -1;
+        return fail("_writeWithType", Error::InvalidArgument);
     }
 
+    let write_type = match WriteType::from_raw(write_type) {
+        Some(t) => t,
+        None => return fail("_writeWithType", Error::InvalidArgument),
+    };
+
     let uuid_str = unsafe {
         let slice = std::slice::from_raw_parts(uuid, uuid_len as usize);
         String::from_utf16_lossy(slice)
@@ -255,35 +308,46 @@ pub unsafe extern "C" fn _write(
         std::slice::from_raw_parts(data, data_len as usize).to_vec()
     };
 
-    #[cfg(windows)]
-    {
-        std::thread::spawn(move || {
-            let _ = gatt::write_characteristic(&uuid_str, &data_vec);
-        });
-        0
-    }
-    #[cfg(not(windows))]
-    {
-        let _ = (uuid_str, data_vec);
-        -1
+    match queue::enqueue(Operation::Write { uuid: uuid_str, data: data_vec, write_type }) {
+        Ok(_) => 0,
+        Err(e) => fail("_writeWithType", e),
     }
 }
 
+/// `chunk_size` is capped at the negotiated MTU; 0 or less uses the MTU.
 #[no_mangle]
 pub unsafe extern "C" fn _writeChunk(
     uuid: *const WideChar,
     uuid_len: i32,
     data: *const u8,
     data_len: i32,
-    _chunk_size: i32,
+    chunk_size: i32,
 ) -> i32 {
-    _write(uuid, uuid_len, data, data_len)
+    if uuid.is_null() || uuid_len <= 0 || data.is_null() || data_len <= 0 {
+        return fail("_writeChunk", Error::InvalidArgument);
+    }
+
+    let uuid_str = unsafe {
+        let slice = std::slice::from_raw_parts(uuid, uuid_len as usize);
+        String::from_utf16_lossy(slice)
+    };
+
+    let data_vec = unsafe {
+        std::slice::from_raw_parts(data, data_len as usize).to_vec()
+    };
+
+    let chunk_size = chunk_size.max(0) as usize;
+
+    match queue::enqueue(Operation::WriteChunked { uuid: uuid_str, data: data_vec, chunk_size }) {
+        Ok(_) => 0,
+        Err(e) => fail("_writeChunk", e),
+    }
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn _enableNotification(uuid: *const WideChar, uuid_len: i32) -> i32 {
     if uuid.is_null() || uuid_len <= 0 {
-        return -1;
+        return fail("_enableNotification", Error::InvalidArgument);
     }
 
     let uuid_str = unsafe {
@@ -291,24 +355,16 @@ pub unsafe extern "C" fn _enableNotification(uuid: *const WideChar, uuid_len: i3
         String::from_utf16_lossy(slice)
     };
 
-    #[cfg(windows)]
-    {
-        std::thread::spawn(move || {
-            let _ = gatt::enable_notification(&uuid_str);
-        });
-        0
-    }
-    #[cfg(not(windows))]
-    {
-        let _ = uuid_str;
-        -1
+    match queue::enqueue(Operation::Subscribe(uuid_str)) {
+        Ok(_) => 0,
+        Err(e) => fail("_enableNotification", e),
     }
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn _disableNotification(uuid: *const WideChar, uuid_len: i32) -> i32 {
     if uuid.is_null() || uuid_len <= 0 {
-        return -1;
+        return fail("_disableNotification", Error::InvalidArgument);
     }
 
     let uuid_str = unsafe {
@@ -316,20 +372,23 @@ pub unsafe extern "C" fn _disableNotification(uuid: *const WideChar, uuid_len: i
         String::from_utf16_lossy(slice)
     };
 
-    #[cfg(windows)]
-    {
-        std::thread::spawn(move || {
-            let _ = gatt::disable_notification(&uuid_str);
-        });
-        0
-    }
-    #[cfg(not(windows))]
-    {
-        let _ = uuid_str;
-        -1
+    match queue::enqueue(Operation::Unsubscribe(uuid_str)) {
+        Ok(_) => 0,
+        Err(e) => fail("_disableNotification", e),
     }
 }
 
+/// 0 Idle, 1 Connecting, 2 Connected, 3 Discovering, 4 Ready, 5 Disconnecting.
+#[no_mangle]
+pub extern "C" fn _getConnectionState() -> i32 {
+    STATE.lock().unwrap().connection_state as i32
+}
+
+#[no_mangle]
+pub extern "C" fn _getQueueDepth() -> i32 {
+    queue::depth() as i32
+}
+
 #[no_mangle]
 pub extern "C" fn _setScanResultHandler(handler: ScanResultHandler) -> i32 {
     let mut state = STATE.lock().unwrap();
@@ -337,6 +396,20 @@ pub extern "C" fn _setScanResultHandler(handler: ScanResultHandler) -> i32 {
     0
 }
 
+#[no_mangle]
+pub extern "C" fn _setScanResultExHandler(handler: ScanResultExHandler) -> i32 {
+    let mut state = STATE.lock().unwrap();
+    state.scan_result_ex_handler = handler;
+    0
+}
+
+#[no_mangle]
+pub extern "C" fn _setDeviceLostHandler(handler: DeviceLostHandler) -> i32 {
+    let mut state = STATE.lock().unwrap();
+    state.device_lost_handler = handler;
+    0
+}
+
 #[no_mangle]
 pub extern "C" fn _setScanStateChangedHandler(handler: ScanStateChangedHandler) -> i32 {
     let mut state = STATE.lock().unwrap();
@@ -371,3 +444,45 @@ pub extern "C" fn _setNotificationHandler(handler: NotificationHandler) -> i32 {
     state.notification_handler = handler;
     0
 }
+
+#[no_mangle]
+pub extern "C" fn _setWriteCompleteHandler(handler: WriteCompleteHandler) -> i32 {
+    let mut state = STATE.lock().unwrap();
+    state.write_complete_handler = handler;
+    0
+}
+
+#[no_mangle]
+pub extern "C" fn _setWriteProgressHandler(handler: WriteProgressHandler) -> i32 {
+    let mut state = STATE.lock().unwrap();
+    state.write_progress_handler = handler;
+    0
+}
+
+#[no_mangle]
+pub extern "C" fn _getLastError() -> i32 {
+    error::last_error().map(|e| e.code()).unwrap_or(0)
+}
+
+/// Copies the calling thread's last error message as NUL-terminated UTF-16
+/// into `buffer` (truncated to `buffer_len` units including the NUL) and
+/// returns the full message length, so a first call with a null buffer can
+/// size the second.
+#[no_mangle]
+pub unsafe extern "C" fn _getLastErrorMessage(buffer: *mut WideChar, buffer_len: i32) -> i32 {
+    let message: Vec<u16> = error::last_error()
+        .map(|e| e.to_string())
+        .unwrap_or_default()
+        .encode_utf16()
+        .collect();
+
+    if !buffer.is_null() && buffer_len > 0 {
+        let copy_len = message.len().min(buffer_len as usize - 1);
+        unsafe {
+            std::ptr::copy_nonoverlapping(message.as_ptr(), buffer, copy_len);
+            *buffer.add(copy_len) = 0;
+        }
+    }
+
+    message.len() as i32
+}
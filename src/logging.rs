@@ -0,0 +1,190 @@
+use crate::config::{self, Config};
+use log::{LevelFilter, Log, Metadata, Record};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const LOG_FILE_NAME: &str = "squaregolf-plugin.log";
+
+static LOGGER: FileLogger = FileLogger {
+    sink: Mutex::new(None),
+};
+
+struct FileLogger {
+    sink: Mutex<Option<Sink>>,
+}
+
+struct Sink {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    max_files: usize,
+}
+
+impl Sink {
+    fn open(path: PathBuf, max_size: u64, max_files: usize) -> Option<Self> {
+        if let Some(dir) = path.parent() {
+            let _ = fs::create_dir_all(dir);
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path).ok()?;
+        let size = file.metadata().map(|m| m.len()).unwrap_or(0);
+        Some(Self { path, file, size, max_size, max_files })
+    }
+
+    fn write_line(&mut self, line: &str) {
+        if self.max_size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate();
+        }
+        if self.file.write_all(line.as_bytes()).is_ok() {
+            self.size += line.len() as u64;
+        }
+    }
+
+    /// Shifts `plugin.log` -> `plugin.log.1` -> ... and drops the oldest.
+    fn rotate(&mut self) {
+        let _ = self.file.flush();
+
+        if self.max_files == 0 {
+            let _ = fs::remove_file(&self.path);
+        } else {
+            let _ = fs::remove_file(rotated_path(&self.path, self.max_files));
+            for n in (1..self.max_files).rev() {
+                let _ = fs::rename(rotated_path(&self.path, n), rotated_path(&self.path, n + 1));
+            }
+            let _ = fs::rename(&self.path, rotated_path(&self.path, 1));
+        }
+
+        if let Ok(file) = OpenOptions::new().create(true).append(true).open(&self.path) {
+            self.file = file;
+            self.size = 0;
+        }
+    }
+}
+
+fn rotated_path(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+impl Log for FileLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let thread = std::thread::current();
+        let line = format!(
+            "{} {:<5} [{}] {}: {}\n",
+            timestamp(),
+            record.level(),
+            thread.name().unwrap_or("-"),
+            record.target(),
+            record.args()
+        );
+
+        if let Some(sink) = self.sink.lock().unwrap().as_mut() {
+            sink.write_line(&line);
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(sink) = self.sink.lock().unwrap().as_mut() {
+            let _ = sink.file.flush();
+        }
+    }
+}
+
+/// Installs the file logger on first call and (re)applies the log settings
+/// from `config` on every call, so `_open` picks up edits to `plugin.ini`.
+pub fn init(config: &Config) {
+    let _ = log::set_logger(&LOGGER);
+    log::set_max_level(config.log_level);
+
+    let mut sink = LOGGER.sink.lock().unwrap();
+
+    if config.log_level == LevelFilter::Off {
+        *sink = None;
+        return;
+    }
+
+    let candidates = match config.log_file {
+        Some(ref path) => vec![path.clone()],
+        None => config::data_dirs()
+            .into_iter()
+            .map(|dir| dir.join(LOG_FILE_NAME))
+            .collect(),
+    };
+
+    if sink.as_ref().is_some_and(|s| candidates.contains(&s.path)) {
+        let s = sink.as_mut().unwrap();
+        s.max_size = config.log_max_size;
+        s.max_files = config.log_max_files;
+        return;
+    }
+
+    *sink = candidates
+        .into_iter()
+        .find_map(|path| Sink::open(path, config.log_max_size, config.log_max_files));
+}
+
+fn timestamp() -> String {
+    format_timestamp(SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default())
+}
+
+/// UTC wall-clock time as `YYYY-MM-DD HH:MM:SS.mmmZ`, without pulling in a
+/// date/time dependency.
+fn format_timestamp(since_epoch: Duration) -> String {
+    let secs = since_epoch.as_secs();
+    let (hour, minute, second) = ((secs / 3600) % 24, (secs / 60) % 60, secs % 60);
+
+    // Howard Hinnant's days-to-civil conversion.
+    let z = (secs / 86400) as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        hour,
+        minute,
+        second,
+        since_epoch.subsec_millis()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_utc_timestamps() {
+        assert_eq!(format_timestamp(Duration::ZERO), "1970-01-01 00:00:00.000Z");
+        assert_eq!(format_timestamp(Duration::from_secs(951_782_400)), "2000-02-29 00:00:00.000Z");
+        assert_eq!(
+            format_timestamp(Duration::from_millis(1_700_000_000_123)),
+            "2023-11-14 22:13:20.123Z"
+        );
+    }
+
+    #[test]
+    fn numbers_rotated_files() {
+        let path = Path::new("logs").join("squaregolf-plugin.log");
+        assert_eq!(rotated_path(&path, 2), Path::new("logs").join("squaregolf-plugin.log.2"));
+    }
+}
@@ -0,0 +1,236 @@
+use std::iter::Peekable;
+use std::str::Chars;
+
+/// A small regular expression for device-name filters, so the plugin does not
+/// need the `regex` crate. Supports literals, `.`, `[...]` classes (ranges and
+/// `^` negation), `\d` `\w` `\s` and escaped characters, the `*` `+` `?`
+/// quantifiers and `^`/`$` anchors. Groups, alternation and `{n,m}` are not
+/// supported and rejected, so they are not silently taken as literals.
+/// Matching takes time polynomial in the name length, as it runs while the
+/// plugin state is locked.
+#[derive(Debug, Clone)]
+pub struct NamePattern {
+    pieces: Vec<(Atom, Repeat)>,
+    anchored_start: bool,
+    anchored_end: bool,
+}
+
+#[derive(Debug, Clone)]
+enum Atom {
+    Any,
+    Char(char),
+    Class { negated: bool, items: Vec<ClassItem> },
+}
+
+#[derive(Debug, Clone)]
+enum ClassItem {
+    Char(char),
+    Range(char, char),
+    Digit,
+    Word,
+    Space,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Repeat {
+    One,
+    ZeroOrOne,
+    ZeroOrMore,
+    OneOrMore,
+}
+
+impl ClassItem {
+    fn matches(&self, c: char) -> bool {
+        match *self {
+            ClassItem::Char(x) => c == x,
+            ClassItem::Range(lo, hi) => (lo..=hi).contains(&c),
+            ClassItem::Digit => c.is_ascii_digit(),
+            ClassItem::Word => c.is_alphanumeric() || c == '_',
+            ClassItem::Space => c.is_whitespace(),
+        }
+    }
+
+    fn from_escape(c: char) -> Self {
+        match c {
+            'd' => ClassItem::Digit,
+            'w' => ClassItem::Word,
+            's' => ClassItem::Space,
+            c => ClassItem::Char(c),
+        }
+    }
+}
+
+impl Atom {
+    fn matches(&self, c: char) -> bool {
+        match self {
+            Atom::Any => true,
+            Atom::Char(x) => c == *x,
+            Atom::Class { negated, items } => items.iter().any(|i| i.matches(c)) != *negated,
+        }
+    }
+}
+
+impl NamePattern {
+    /// Returns `None` for a malformed pattern (unterminated class, dangling
+    /// escape, quantifier with nothing to repeat) or one using unsupported
+    /// syntax (`(`, `)`, `|`, `{`, `}`; escape them to match literally).
+    pub fn parse(pattern: &str) -> Option<Self> {
+        let mut chars = pattern.chars().peekable();
+        let anchored_start = chars.next_if_eq(&'^').is_some();
+        let mut anchored_end = false;
+        let mut pieces = Vec::new();
+
+        while let Some(c) = chars.next() {
+            let atom = match c {
+                '$' if chars.peek().is_none() => {
+                    anchored_end = true;
+                    break;
+                }
+                '.' => Atom::Any,
+                '\\' => match ClassItem::from_escape(chars.next()?) {
+                    ClassItem::Char(c) => Atom::Char(c),
+                    item => Atom::Class { negated: false, items: vec![item] },
+                },
+                '[' => parse_class(&mut chars)?,
+                '*' | '+' | '?' | '(' | ')' | '|' | '{' | '}' => return None,
+                c => Atom::Char(c),
+            };
+
+            let repeat = match chars.peek() {
+                Some('*') => Repeat::ZeroOrMore,
+                Some('+') => Repeat::OneOrMore,
+                Some('?') => Repeat::ZeroOrOne,
+                _ => Repeat::One,
+            };
+            if repeat != Repeat::One {
+                chars.next();
+            }
+
+            pieces.push((atom, repeat));
+        }
+
+        Some(Self { pieces, anchored_start, anchored_end })
+    }
+
+    pub fn is_match(&self, text: &str) -> bool {
+        let text: Vec<char> = text.chars().collect();
+        let mut failed = vec![false; (self.pieces.len() + 1) * (text.len() + 1)];
+        if self.anchored_start {
+            return self.match_at(0, &text, 0, &mut failed);
+        }
+        (0..=text.len()).any(|start| self.match_at(0, &text, start, &mut failed))
+    }
+
+    /// Greedy backtracking match of `pieces[piece..]` against `text[pos..]`.
+    /// `failed` remembers the `(piece, pos)` pairs already known not to
+    /// match, so each is tried at most once.
+    fn match_at(&self, piece: usize, text: &[char], pos: usize, failed: &mut [bool]) -> bool {
+        let Some((atom, repeat)) = self.pieces.get(piece) else {
+            return !self.anchored_end || pos == text.len();
+        };
+
+        let key = piece * (text.len() + 1) + pos;
+        if failed[key] {
+            return false;
+        }
+
+        let (min, max) = match repeat {
+            Repeat::One => (1, 1),
+            Repeat::ZeroOrOne => (0, 1),
+            Repeat::ZeroOrMore => (0, usize::MAX),
+            Repeat::OneOrMore => (1, usize::MAX),
+        };
+
+        let mut count = 0;
+        while count < max && pos + count < text.len() && atom.matches(text[pos + count]) {
+            count += 1;
+        }
+
+        let matched =
+            count >= min && (min..=count).rev().any(|n| self.match_at(piece + 1, text, pos + n, failed));
+        failed[key] = !matched;
+        matched
+    }
+}
+
+fn parse_class(chars: &mut Peekable<Chars>) -> Option<Atom> {
+    let negated = chars.next_if_eq(&'^').is_some();
+    let mut items = Vec::new();
+
+    loop {
+        match chars.next()? {
+            ']' => break,
+            '\\' => items.push(ClassItem::from_escape(chars.next()?)),
+            c => {
+                let mut lookahead = chars.clone();
+                if lookahead.next() == Some('-') {
+                    if let Some(end) = lookahead.next().filter(|&e| e != ']') {
+                        chars.next();
+                        chars.next();
+                        items.push(ClassItem::Range(c, end));
+                        continue;
+                    }
+                }
+                items.push(ClassItem::Char(c));
+            }
+        }
+    }
+
+    Some(Atom::Class { negated, items })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_match(pattern: &str, text: &str) -> bool {
+        NamePattern::parse(pattern).unwrap().is_match(text)
+    }
+
+    #[test]
+    fn matches_literals_anywhere_unless_anchored() {
+        assert!(is_match("Golf", "SquareGolf"));
+        assert!(!is_match("^Golf", "SquareGolf"));
+        assert!(is_match("Golf$", "SquareGolf"));
+        assert!(!is_match("Square$", "SquareGolf"));
+        assert!(is_match("", "anything"));
+    }
+
+    #[test]
+    fn matches_classes_and_escapes() {
+        assert!(is_match("^SquareGolf-[0-9A-F]+$", "SquareGolf-1A2B"));
+        assert!(!is_match("^SquareGolf-[0-9A-F]+$", "SquareGolf-1a2b"));
+        assert!(is_match("^[^x]+$", "abc"));
+        assert!(!is_match("^[^x]+$", "axc"));
+        assert!(is_match("^SG\\d\\d$", "SG42"));
+        assert!(is_match("^a\\.b$", "a.b"));
+        assert!(!is_match("^a\\.b$", "axb"));
+        assert!(is_match("^[a-]$", "-"));
+    }
+
+    #[test]
+    fn quantifiers_backtrack() {
+        assert!(is_match("^a.*c$", "abbbc"));
+        assert!(is_match("^ab?c$", "ac"));
+        assert!(is_match("^ab?c$", "abc"));
+        assert!(!is_match("^ab+c$", "ac"));
+        assert!(is_match("^a*a$", "aaaa"));
+    }
+
+    #[test]
+    fn rejects_malformed_patterns() {
+        assert!(NamePattern::parse("*a").is_none());
+        assert!(NamePattern::parse("[ab").is_none());
+        assert!(NamePattern::parse("ab\\").is_none());
+        assert!(NamePattern::parse("^(SG|SquareGolf)$").is_none());
+        assert!(NamePattern::parse("SG-\\d{4}").is_none());
+        assert!(is_match("^a\\(b\\)$", "a(b)"));
+    }
+
+    #[test]
+    fn nested_quantifiers_do_not_backtrack_exponentially() {
+        let name = "a".repeat(200);
+        assert!(!is_match(&format!("{}b", "a*".repeat(20)), &name));
+        assert!(is_match(&format!("^{}$", "a*".repeat(20)), &name));
+    }
+}
@@ -0,0 +1,148 @@
+use crate::backend::WriteType;
+use crate::client;
+use crate::connection;
+use crate::error::{self, Error, Result};
+use parking_lot::{ReentrantMutex, ReentrantMutexGuard};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Condvar, LazyLock, Mutex};
+use std::thread::{self, ThreadId};
+
+const QUEUE_CAPACITY: usize = 64;
+
+/// Held by the worker while an operation runs, and by GATT requests made
+/// outside the queue (`_read`, discovery), so no two requests overlap.
+/// Reentrant because callbacks fired while it is held may call back into the
+/// plugin on the same thread.
+static GATT_LOCK: ReentrantMutex<()> = ReentrantMutex::new(());
+
+static QUEUE: LazyLock<OperationQueue> = LazyLock::new(|| {
+    thread::Builder::new()
+        .name("gatt-queue".to_string())
+        .spawn(worker)
+        .expect("failed to spawn GATT queue worker");
+    OperationQueue::new()
+});
+
+#[derive(Debug, Clone)]
+pub enum Operation {
+    Connect(String),
+    Reconnect { address: u64, name: String, attempt: u32, generation: u64 },
+    Write { uuid: String, data: Vec<u8>, write_type: WriteType },
+    WriteChunked { uuid: String, data: Vec<u8>, chunk_size: usize },
+    Subscribe(String),
+    Unsubscribe(String),
+}
+
+struct OperationQueue {
+    pending: Mutex<VecDeque<(Operation, Option<ThreadId>)>>,
+    available: Condvar,
+    in_flight: AtomicBool,
+}
+
+impl OperationQueue {
+    fn new() -> Self {
+        Self {
+            pending: Mutex::new(VecDeque::new()),
+            available: Condvar::new(),
+            in_flight: AtomicBool::new(false),
+        }
+    }
+}
+
+/// Queues a GATT operation behind everything already submitted. Operations
+/// run one at a time on a single worker thread, in submission order.
+pub fn enqueue(op: Operation) -> Result<()> {
+    push(op, Some(thread::current().id()))
+}
+
+/// Queues an operation the plugin started on its own (reconnects,
+/// auto-connects). Its failure is only logged: the plugin's threads never read
+/// their last error, so recording it would just leave an entry behind.
+pub fn enqueue_internal(op: Operation) -> Result<()> {
+    push(op, None)
+}
+
+fn push(op: Operation, origin: Option<ThreadId>) -> Result<()> {
+    let mut pending = QUEUE.pending.lock().unwrap();
+    if pending.len() >= QUEUE_CAPACITY {
+        log::warn!("GATT queue full, dropping {:?}", op);
+        return Err(Error::QueueFull);
+    }
+    pending.push_back((op, origin));
+    QUEUE.available.notify_one();
+    Ok(())
+}
+
+/// Discards every queued operation. Discarded writes still get their
+/// write-complete callback, with status -1, so the game isn't left waiting.
+pub fn clear() {
+    let discarded: Vec<_> = QUEUE.pending.lock().unwrap().drain(..).collect();
+    if !discarded.is_empty() {
+        log::info!("Discarding {} queued GATT operations", discarded.len());
+    }
+
+    for (op, _) in discarded {
+        if let Operation::Write { uuid, .. } | Operation::WriteChunked { uuid, .. } = op {
+            client::report_write_complete(&uuid, &Err(Error::NotConnected));
+        }
+    }
+}
+
+/// Waits for the running operation, if any, and keeps the worker from
+/// starting another until the guard is dropped.
+pub fn exclusive() -> ReentrantMutexGuard<'static, ()> {
+    GATT_LOCK.lock()
+}
+
+/// Number of operations waiting to run, plus the one currently running.
+pub fn depth() -> usize {
+    let queued = QUEUE.pending.lock().unwrap().len();
+    queued + QUEUE.in_flight.load(Ordering::SeqCst) as usize
+}
+
+fn worker() {
+    loop {
+        let (op, origin) = {
+            let mut pending = QUEUE.pending.lock().unwrap();
+            loop {
+                if let Some(entry) = pending.pop_front() {
+                    QUEUE.in_flight.store(true, Ordering::SeqCst);
+                    break entry;
+                }
+                pending = QUEUE.available.wait(pending).unwrap();
+            }
+        };
+
+        {
+            let _gatt = exclusive();
+            run(op, origin);
+        }
+
+        QUEUE.in_flight.store(false, Ordering::SeqCst);
+    }
+}
+
+fn run(op: Operation, origin: Option<ThreadId>) {
+    log::debug!("Running {:?}", op);
+
+    let result = match &op {
+        Operation::Connect(target) => connection::connect(target),
+        Operation::Write { uuid, data, write_type } => client::write(uuid, data, *write_type),
+        Operation::WriteChunked { uuid, data, chunk_size } => {
+            client::write_chunked(uuid, data, *chunk_size)
+        }
+        Operation::Reconnect { address, name, attempt, generation } => {
+            connection::reconnect(*address, name, *attempt, *generation)
+        }
+        Operation::Subscribe(uuid) => client::subscribe(uuid),
+        Operation::Unsubscribe(uuid) => client::unsubscribe(uuid),
+    };
+
+    if let Err(e) = result {
+        log::warn!("{:?} failed: {}", op, e);
+        if let Some(origin) = origin {
+            error::set_last_error_for(origin, e);
+        }
+    }
+}
@@ -0,0 +1,596 @@
+use crate::backend::{self, Advertisement, AdvertisementData, ManufacturerData};
+use crate::callbacks::{
+    invoke_device_lost, invoke_scan_result, invoke_scan_result_ex, invoke_scan_state_changed,
+};
+use crate::config::Config;
+use crate::error::Result;
+use crate::pattern::NamePattern;
+use crate::queue::{self, Operation};
+use crate::state::STATE;
+use std::collections::VecDeque;
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_DEVICE_NAME: &str = "SquareGolf";
+
+/// Values passed to the scan-state callback. A scan stopped by the Bluetooth
+/// stack reports the negated `BluetoothError` instead, e.g. -1 for
+/// `RadioNotAvailable` or -7 for `DisabledByUser`.
+pub const SCAN_STATE_STOPPED: i32 = 0;
+pub const SCAN_STATE_STARTED: i32 = 1;
+
+/// How the configured filter criteria combine: `Any` reports a device that
+/// satisfies at least one of them, `All` only one that satisfies every one.
+/// Name prefixes and name patterns count as a single criterion.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterMode {
+    Any,
+    All,
+}
+
+impl FilterMode {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "any" | "or" => Some(FilterMode::Any),
+            "all" | "and" => Some(FilterMode::All),
+            _ => None,
+        }
+    }
+}
+
+/// Whether the watcher asks devices for their scan response. Passive scanning
+/// uses less power but misses names that are only sent in the scan response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScanMode {
+    Active,
+    Passive,
+}
+
+impl ScanMode {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "active" => Some(ScanMode::Active),
+            "passive" => Some(ScanMode::Passive),
+            _ => None,
+        }
+    }
+}
+
+/// Matches a manufacturer data entry by company ID and, optionally, the
+/// leading bytes of its payload. Written as `FFFF` or `FFFF:0100` (hex).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManufacturerFilter {
+    pub company_id: u16,
+    pub prefix: Vec<u8>,
+}
+
+impl ManufacturerFilter {
+    pub fn parse(value: &str) -> Option<Self> {
+        let (company, prefix) = value.split_once(':').unwrap_or((value, ""));
+        let company_id = u16::from_str_radix(company.trim(), 16).ok()?;
+
+        let prefix = prefix.trim();
+        if prefix.len() % 2 != 0 {
+            return None;
+        }
+        let prefix = (0..prefix.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(prefix.get(i..i + 2)?, 16).ok())
+            .collect::<Option<Vec<u8>>>()?;
+
+        Some(Self { company_id, prefix })
+    }
+
+    fn matches(&self, entry: &ManufacturerData) -> bool {
+        entry.company_id == self.company_id && entry.data.starts_with(&self.prefix)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ScanFilter {
+    pub enabled: bool,
+    pub mode: FilterMode,
+    pub names: Vec<String>,
+    pub name_patterns: Vec<NamePattern>,
+    pub service_uuids: Vec<String>,
+    pub manufacturers: Vec<ManufacturerFilter>,
+}
+
+impl Default for ScanFilter {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            mode: FilterMode::Any,
+            names: vec![DEFAULT_DEVICE_NAME.to_string()],
+            name_patterns: Vec::new(),
+            service_uuids: Vec::new(),
+            manufacturers: Vec::new(),
+        }
+    }
+}
+
+impl ScanFilter {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            enabled: true,
+            mode: config.filter_mode,
+            names: config.name_filters.clone(),
+            name_patterns: config.name_patterns.clone(),
+            service_uuids: config.service_filters.clone(),
+            manufacturers: config.manufacturer_filters.clone(),
+        }
+    }
+
+    /// Checks a device against the filter using everything it has advertised
+    /// so far. A filter with no criteria matches every device.
+    pub fn matches(&self, name: &str, data: &AdvertisementData) -> bool {
+        if !self.enabled {
+            return true;
+        }
+
+        let mut results = Vec::with_capacity(3);
+        if !self.names.is_empty() || !self.name_patterns.is_empty() {
+            results.push(
+                self.names.iter().any(|n| name.starts_with(n.as_str()))
+                    || self.name_patterns.iter().any(|p| p.is_match(name)),
+            );
+        }
+        if !self.service_uuids.is_empty() {
+            results.push(
+                data.service_uuids
+                    .iter()
+                    .any(|uuid| self.service_uuids.contains(&uuid.to_lowercase())),
+            );
+        }
+        if !self.manufacturers.is_empty() {
+            results.push(
+                data.manufacturer_data
+                    .iter()
+                    .any(|entry| self.manufacturers.iter().any(|f| f.matches(entry))),
+            );
+        }
+
+        match self.mode {
+            FilterMode::Any => results.is_empty() || results.contains(&true),
+            FilterMode::All => !results.contains(&false),
+        }
+    }
+
+    /// Whether a device can be picked out by its advertisement payload alone,
+    /// in which case it is reported even before (or without) a local name.
+    fn selects_unnamed(&self) -> bool {
+        self.enabled && (!self.service_uuids.is_empty() || !self.manufacturers.is_empty())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DiscoveredDevice {
+    pub address: u64,
+    pub name: String,
+    /// Moving average of the most recent `rssi_samples`.
+    pub rssi: i32,
+    pub rssi_samples: VecDeque<i32>,
+    pub last_seen: Instant,
+    pub last_reported: Option<Instant>,
+    pub data: AdvertisementData,
+}
+
+impl DiscoveredDevice {
+    fn new(address: u64) -> Self {
+        Self {
+            address,
+            name: String::new(),
+            rssi: 0,
+            rssi_samples: VecDeque::new(),
+            last_seen: Instant::now(),
+            last_reported: None,
+            data: AdvertisementData::default(),
+        }
+    }
+
+    fn add_rssi_sample(&mut self, rssi: i32, window: usize) {
+        self.rssi_samples.push_back(rssi);
+        while self.rssi_samples.len() > window.max(1) {
+            self.rssi_samples.pop_front();
+        }
+        let sum: i32 = self.rssi_samples.iter().sum();
+        self.rssi = sum / self.rssi_samples.len() as i32;
+    }
+}
+
+pub fn format_address(address: u64) -> String {
+    format!("{:012X}", address)
+}
+
+/// Parses a Bluetooth address written as the scan callback reports it
+/// (`AABBCCDDEEFF`) or with `:` or `-` between the bytes (`AA:BB:CC:DD:EE:FF`).
+pub fn parse_address(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let hex = if s.len() == 17 {
+        let bytes: Vec<&str> = s.split([':', '-']).collect();
+        if bytes.len() != 6 || bytes.iter().any(|b| b.len() != 2) {
+            return None;
+        }
+        bytes.concat()
+    } else {
+        s.to_string()
+    };
+
+    if hex.len() != 12 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    u64::from_str_radix(&hex, 16).ok()
+}
+
+/// Starts (or re-arms) scanning. With a `timeout` the scan stops on its own
+/// and, if `auto_connect` is set, connects to the strongest matching device
+/// seen during the window.
+pub fn start_scan(enable_filter: bool, timeout: Option<Duration>, auto_connect: bool) -> Result<()> {
+    {
+        let mut state = STATE.lock().unwrap();
+        state.scan_filter.enabled = enable_filter;
+    }
+
+    log::info!(
+        "Starting scan (filter {}, timeout {:?})",
+        if enable_filter { "on" } else { "off" },
+        timeout
+    );
+
+    // Mark the scan as running before the watcher starts, so a stop it
+    // reports straight away is not overwritten afterwards.
+    let (session, handler, newly_started, device_timeout) = {
+        let mut state = STATE.lock().unwrap();
+        state.scan_session += 1;
+        let newly_started = !std::mem::replace(&mut state.scanning, true);
+        (
+            state.scan_session,
+            state.scan_state_changed_handler,
+            newly_started,
+            state.config.device_timeout,
+        )
+    };
+
+    if let Err(e) = backend::current().start_scan() {
+        let mut state = STATE.lock().unwrap();
+        if state.scan_session == session {
+            state.scan_session -= 1;
+            if newly_started {
+                state.scanning = false;
+            }
+        }
+        return Err(e);
+    }
+
+    if let Some(timeout) = timeout {
+        schedule_scan_timeout(session, timeout, auto_connect);
+    }
+    if let Some(device_timeout) = device_timeout {
+        spawn_device_aging(session, device_timeout);
+    }
+
+    if newly_started {
+        invoke_scan_state_changed(handler, SCAN_STATE_STARTED);
+    }
+    Ok(())
+}
+
+/// Replaces the filter criteria. Whether filtering is on is still decided by
+/// each `_startScan` call; devices already listed are not re-checked.
+pub fn set_filter(filter: ScanFilter) {
+    log::info!("Scan filter set to {:?}", filter);
+    let mut state = STATE.lock().unwrap();
+    let enabled = state.scan_filter.enabled;
+    state.scan_filter = ScanFilter { enabled, ..filter };
+}
+
+fn schedule_scan_timeout(session: u64, timeout: Duration, auto_connect: bool) {
+    let started = Instant::now();
+
+    let spawned = thread::Builder::new()
+        .name("scan-timeout".to_string())
+        .spawn(move || {
+            thread::sleep(timeout);
+
+            {
+                let state = STATE.lock().unwrap();
+                if state.scan_session != session || !state.scanning {
+                    return;
+                }
+            }
+
+            log::info!("Scan timed out after {:?}", timeout);
+            stop_scan();
+
+            if auto_connect {
+                connect_strongest(started);
+            }
+        });
+
+    if let Err(e) = spawned {
+        log::warn!("Could not spawn scan timer: {}", e);
+    }
+}
+
+/// Evicts devices that stopped advertising while this scan session runs.
+/// Nothing ages out between scans, since no advertisements arrive then.
+fn spawn_device_aging(session: u64, device_timeout: Duration) {
+    let interval = (device_timeout / 2).min(Duration::from_secs(1));
+
+    let spawned = thread::Builder::new()
+        .name("device-aging".to_string())
+        .spawn(move || loop {
+            thread::sleep(interval);
+
+            {
+                let state = STATE.lock().unwrap();
+                if state.scan_session != session || !state.scanning {
+                    return;
+                }
+            }
+
+            evict_stale_devices(device_timeout);
+        });
+
+    if let Err(e) = spawned {
+        log::warn!("Could not spawn device aging timer: {}", e);
+    }
+}
+
+fn evict_stale_devices(device_timeout: Duration) {
+    let (handler, lost) = {
+        let mut state = STATE.lock().unwrap();
+        let connected = state
+            .connection_state
+            .is_connected()
+            .then_some(state.device_address);
+
+        let lost: Vec<DiscoveredDevice> = state
+            .discovered_devices
+            .values()
+            .filter(|d| d.last_seen.elapsed() > device_timeout && Some(d.address) != connected)
+            .cloned()
+            .collect();
+
+        for device in &lost {
+            state.discovered_devices.remove(&device.address);
+        }
+
+        (state.device_lost_handler, lost)
+    };
+
+    for device in lost {
+        log::info!("Lost {} \"{}\"", format_address(device.address), device.name);
+        if device.last_reported.is_some() {
+            invoke_device_lost(handler, &format_address(device.address));
+        }
+    }
+}
+
+fn connect_strongest(since: Instant) {
+    let strongest = {
+        let state = STATE.lock().unwrap();
+        state
+            .discovered_devices
+            .values()
+            .filter(|d| d.last_seen >= since && d.last_reported.is_some())
+            .filter(|d| state.scan_filter.matches(&d.name, &d.data))
+            .filter(|d| state.config.min_rssi.is_none_or(|min| d.rssi >= min))
+            .max_by_key(|d| d.rssi)
+            .map(|d| (d.address, d.name.clone(), d.rssi))
+    };
+
+    match strongest {
+        Some((address, name, rssi)) => {
+            log::info!("Auto-connecting to {} ({}) rssi {}", name, format_address(address), rssi);
+            if let Err(e) = queue::enqueue_internal(Operation::Connect(format_address(address))) {
+                log::warn!("Could not queue auto-connect: {}", e);
+            }
+        }
+        None => log::info!("No matching device found to auto-connect to"),
+    }
+}
+
+pub fn stop_scan() {
+    log::info!("Stopping scan");
+    backend::current().stop_scan();
+    handle_scan_stopped(0);
+}
+
+/// Reports the end of a scan, whether requested or caused by the stack.
+/// `error` is the `BluetoothError` value, 0 for a normal stop.
+pub fn handle_scan_stopped(error: i32) {
+    let handler = {
+        let mut state = STATE.lock().unwrap();
+        if !std::mem::replace(&mut state.scanning, false) {
+            return;
+        }
+        state.scan_state_changed_handler
+    };
+
+    if error == 0 {
+        log::info!("Scan stopped");
+        invoke_scan_state_changed(handler, SCAN_STATE_STOPPED);
+    } else {
+        log::warn!("Scan stopped by the Bluetooth stack (BluetoothError {})", error);
+        invoke_scan_state_changed(handler, -error);
+    }
+}
+
+pub fn handle_advertisement(adv: Advertisement) {
+    let (handler, ex_handler, device) = {
+        let mut guard = STATE.lock().unwrap();
+        let state = &mut *guard;
+
+        // Names often arrive only in the scan response, so keep the last one seen.
+        let name = if adv.name.is_empty() {
+            state
+                .discovered_devices
+                .get(&adv.address)
+                .map(|d| d.name.clone())
+                .unwrap_or_default()
+        } else {
+            adv.name.clone()
+        };
+
+        let mut data = state
+            .discovered_devices
+            .get(&adv.address)
+            .map(|d| d.data.clone())
+            .unwrap_or_default();
+        data.merge(adv.data);
+
+        if !state.scan_filter.matches(&name, &data) {
+            return;
+        }
+
+        let config = &state.config;
+        let device = state.discovered_devices.entry(adv.address).or_insert_with(|| {
+            log::info!("Discovered {} \"{}\" rssi {}", format_address(adv.address), name, adv.rssi);
+            DiscoveredDevice::new(adv.address)
+        });
+
+        let now = Instant::now();
+        device.name = name;
+        device.last_seen = now;
+        device.add_rssi_sample(adv.rssi, config.rssi_window);
+        device.data = data;
+
+        if (device.name.is_empty() && !state.scan_filter.selects_unnamed())
+            || config.min_rssi.is_some_and(|min| device.rssi < min) {
+            return;
+        }
+
+        // Advertisements arrive several times a second; report each device
+        // at most once per interval so the game's list stays readable.
+        if device
+            .last_reported
+            .is_some_and(|last| now.duration_since(last) < config.report_interval)
+        {
+            return;
+        }
+        device.last_reported = Some(now);
+
+        (state.scan_result_handler, state.scan_result_ex_handler, device.clone())
+    };
+
+    let address = format_address(device.address);
+    invoke_scan_result(handler, &address, &device.name, device.rssi);
+    invoke_scan_result_ex(ex_handler, &address, &device.name, device.rssi, &device.data);
+}
+
+/// Resolves the string passed to `_connect`: either an address or an
+/// advertised name. When several devices share a name, the most recently seen
+/// one wins. An address that no scan has reported is still returned (with an
+/// empty name), so a known unit can be connected to without scanning first.
+pub fn resolve_device(target: &str) -> Option<(u64, String)> {
+    let state = STATE.lock().unwrap();
+
+    if let Some(address) = parse_address(target) {
+        let name = state.discovered_devices.get(&address).map(|d| d.name.clone());
+        return Some((address, name.unwrap_or_default()));
+    }
+
+    let named = || state.discovered_devices.values().filter(|d| !d.name.is_empty());
+
+    named()
+        .filter(|d| d.name == target)
+        .max_by_key(|d| d.last_seen)
+        .or_else(|| {
+            named()
+                .filter(|d| target.starts_with(d.name.as_str()))
+                .max_by_key(|d| d.last_seen)
+        })
+        .map(|d| (d.address, d.name.clone()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn advertised(service_uuids: &[&str], manufacturer: Option<(u16, &[u8])>) -> AdvertisementData {
+        AdvertisementData {
+            service_uuids: service_uuids.iter().map(|u| u.to_string()).collect(),
+            manufacturer_data: manufacturer
+                .map(|(company_id, data)| ManufacturerData { company_id, data: data.to_vec() })
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn parses_addresses() {
+        assert_eq!(parse_address("C0FFEE000001"), Some(0xC0FFEE000001));
+        assert_eq!(parse_address("c0:ff:ee:00:00:01"), Some(0xC0FFEE000001));
+        assert_eq!(parse_address("C0-FF-EE-00-00-01"), Some(0xC0FFEE000001));
+        assert_eq!(parse_address(" C0FFEE000001 "), Some(0xC0FFEE000001));
+        assert_eq!(parse_address("C0FFEE00001"), None);
+        assert_eq!(parse_address("C0:FF:EE:0000:01"), None);
+        assert_eq!(parse_address("SquareGolf12"), None);
+        assert_eq!(format_address(0xC0FFEE000001), "C0FFEE000001");
+    }
+
+    #[test]
+    fn parses_manufacturer_filters() {
+        assert_eq!(
+            ManufacturerFilter::parse("FFFF:0100"),
+            Some(ManufacturerFilter { company_id: 0xFFFF, prefix: vec![0x01, 0x00] })
+        );
+        assert_eq!(
+            ManufacturerFilter::parse("004c"),
+            Some(ManufacturerFilter { company_id: 0x004C, prefix: Vec::new() })
+        );
+        assert_eq!(ManufacturerFilter::parse("GGGG"), None);
+        assert_eq!(ManufacturerFilter::parse("FFFF:010"), None);
+        assert_eq!(ManufacturerFilter::parse("FFFF:zz"), None);
+    }
+
+    #[test]
+    fn name_prefixes_and_patterns_form_one_criterion() {
+        let filter = ScanFilter {
+            names: vec!["SquareGolf".to_string()],
+            name_patterns: vec![NamePattern::parse("^SG-\\d+$").unwrap()],
+            ..ScanFilter::default()
+        };
+        let data = AdvertisementData::default();
+
+        assert!(filter.matches("SquareGolf 2", &data));
+        assert!(filter.matches("SG-42", &data));
+        assert!(!filter.matches("SG-Pro", &data));
+        assert!(!filter.matches("", &data));
+    }
+
+    #[test]
+    fn filter_mode_combines_criteria() {
+        let mut filter = ScanFilter {
+            service_uuids: vec!["86602100-6b7e-439a-bdd1-489a3213e9bb".to_string()],
+            manufacturers: vec![ManufacturerFilter { company_id: 0xFFFF, prefix: vec![0x01] }],
+            ..ScanFilter::default()
+        };
+        let both = advertised(&["86602100-6B7E-439A-BDD1-489A3213E9BB"], Some((0xFFFF, &[0x01, 0x00])));
+        let uuid_only = advertised(&["86602100-6b7e-439a-bdd1-489a3213e9bb"], Some((0xFFFF, &[0x02])));
+        let nothing = advertised(&[], None);
+
+        assert!(filter.matches("", &both));
+        assert!(filter.matches("", &uuid_only));
+        assert!(filter.matches("SquareGolf", &nothing));
+        assert!(!filter.matches("Headphones", &nothing));
+
+        filter.mode = FilterMode::All;
+        assert!(filter.matches("SquareGolf", &both));
+        assert!(!filter.matches("", &both));
+        assert!(!filter.matches("SquareGolf", &uuid_only));
+    }
+
+    #[test]
+    fn empty_or_disabled_filter_matches_everything() {
+        let data = AdvertisementData::default();
+        let empty = ScanFilter { names: Vec::new(), ..ScanFilter::default() };
+        assert!(empty.matches("Headphones", &data));
+
+        let disabled = ScanFilter { enabled: false, ..ScanFilter::default() };
+        assert!(disabled.matches("Headphones", &data));
+        assert!(!disabled.selects_unnamed());
+    }
+}
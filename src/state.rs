@@ -1,100 +1,141 @@
 use std::sync::Mutex;
+use crate::backend::{self, BleBackend};
 use crate::callbacks::*;
-use std::collections::HashMap;
-use std::sync::LazyLock;
-use std::sync::atomic::AtomicBool;
-use send_wrapper::SendWrapper;
-
-pub static DISCOVERY_IN_PROGRESS: AtomicBool = AtomicBool::new(false);
+use crate::config::{self, Config};
+use crate::connection::ConnectionState;
+use crate::error::Result;
+use crate::last_device::{self, AutoConnect};
+use crate::scan::{DiscoveredDevice, ScanFilter};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Condvar, LazyLock};
+
+/// Set while a `_discoverServices` call runs; callers arriving meanwhile wait
+/// on `DISCOVERY_DONE` until it is cleared.
+pub static DISCOVERY_IN_PROGRESS: Mutex<bool> = Mutex::new(false);
+pub static DISCOVERY_DONE: Condvar = Condvar::new();
 
 #[cfg(windows)]
-use windows::Devices::Bluetooth::Advertisement::{BluetoothLEAdvertisementWatcher, BluetoothLEAdvertisementReceivedEventArgs};
+use windows::Devices::Bluetooth::Advertisement::BluetoothLEAdvertisementWatcher;
 #[cfg(windows)]
 use windows::Devices::Bluetooth::BluetoothLEDevice;
 #[cfg(windows)]
-use windows::Devices::Bluetooth::GenericAttributeProfile::{GattDeviceService, GattCharacteristic, GattValueChangedEventArgs};
-#[cfg(windows)]
-use windows::Foundation::TypedEventHandler;
+use windows::Devices::Bluetooth::GenericAttributeProfile::{GattDeviceService, GattCharacteristic, GattSession};
 #[cfg(windows)]
-use windows::core::IInspectable;
+use windows::Foundation::EventRegistrationToken;
 
 pub static STATE: LazyLock<Mutex<PluginState>> = LazyLock::new(|| Mutex::new(PluginState::new()));
 
 pub struct PluginState {
+    pub backend: Arc<dyn BleBackend>,
+    pub config: Config,
     pub initialized: bool,
-    pub is_connected: bool,
+    pub connection_state: ConnectionState,
+    pub connection_generation: u64,
     pub device_name: Option<String>,
     pub device_address: u64,
     pub battery_level: i32,
     pub signal_strength: i32,
 
     pub scan_result_handler: ScanResultHandler,
+    pub scan_result_ex_handler: ScanResultExHandler,
+    pub device_lost_handler: DeviceLostHandler,
     pub scan_state_changed_handler: ScanStateChangedHandler,
     pub connection_state_handler: ConnectionStateHandler,
     pub service_discovered_handler: ServiceDiscoveredHandler,
     pub value_changed_handler: ValueChangedHandler,
     pub notification_handler: NotificationHandler,
+    pub write_complete_handler: WriteCompleteHandler,
+    pub write_progress_handler: WriteProgressHandler,
 
     #[cfg(windows)]
     pub watcher: Option<BluetoothLEAdvertisementWatcher>,
     #[cfg(windows)]
     pub device: Option<BluetoothLEDevice>,
     #[cfg(windows)]
+    pub gatt_session: Option<GattSession>,
+    #[cfg(windows)]
     pub services: HashMap<String, GattDeviceService>,
     #[cfg(windows)]
     pub characteristics: HashMap<String, GattCharacteristic>,
     #[cfg(windows)]
-    pub notification_handlers: HashMap<String, SendWrapper<TypedEventHandler<GattCharacteristic, GattValueChangedEventArgs>>>,
+    pub notification_tokens: HashMap<String, EventRegistrationToken>,
     #[cfg(windows)]
-    pub watcher_handler: Option<SendWrapper<TypedEventHandler<BluetoothLEAdvertisementWatcher, BluetoothLEAdvertisementReceivedEventArgs>>>,
+    pub watcher_token: Option<EventRegistrationToken>,
     #[cfg(windows)]
-    pub connection_status_handler: Option<SendWrapper<TypedEventHandler<BluetoothLEDevice, IInspectable>>>,
-
-    pub discovered_devices: HashMap<String, u64>,
+    pub watcher_stopped_token: Option<EventRegistrationToken>,
+    #[cfg(windows)]
+    pub connection_status_token: Option<EventRegistrationToken>,
+
+    pub scan_filter: ScanFilter,
+    pub scanning: bool,
+    /// Bumped by every `_startScan`, so a timer from an earlier scan never
+    /// stops a later one.
+    pub scan_session: u64,
+    pub discovered_devices: HashMap<u64, DiscoveredDevice>,
     pub read_cache: HashMap<String, Vec<u8>>,
     pub read_first_pass_buffer: HashMap<String, usize>,
+    /// Characteristics with notifications enabled, restored after a reconnect.
+    pub subscriptions: HashSet<String>,
+    /// Set on `_open` until the remembered device has been tried once.
+    pub auto_connect_pending: bool,
 }
 
 impl PluginState {
     pub fn new() -> Self {
         Self {
+            backend: backend::default_backend(),
+            config: Config::default(),
             initialized: false,
-            is_connected: false,
+            connection_state: ConnectionState::Idle,
+            connection_generation: 0,
             device_name: None,
             device_address: 0,
             battery_level: -1,
             signal_strength: -100,
 
             scan_result_handler: None,
+            scan_result_ex_handler: None,
+            device_lost_handler: None,
             scan_state_changed_handler: None,
             connection_state_handler: None,
             service_discovered_handler: None,
             value_changed_handler: None,
             notification_handler: None,
+            write_complete_handler: None,
+            write_progress_handler: None,
 
             #[cfg(windows)]
             watcher: None,
             #[cfg(windows)]
             device: None,
             #[cfg(windows)]
+            gatt_session: None,
+            #[cfg(windows)]
             services: HashMap::new(),
             #[cfg(windows)]
             characteristics: HashMap::new(),
             #[cfg(windows)]
-            notification_handlers: HashMap::new(),
+            notification_tokens: HashMap::new(),
             #[cfg(windows)]
-            watcher_handler: None,
+            watcher_token: None,
             #[cfg(windows)]
-            connection_status_handler: None,
+            watcher_stopped_token: None,
+            #[cfg(windows)]
+            connection_status_token: None,
 
+            scan_filter: ScanFilter::default(),
+            scanning: false,
+            scan_session: 0,
             discovered_devices: HashMap::new(),
             read_cache: HashMap::new(),
             read_first_pass_buffer: HashMap::new(),
+            subscriptions: HashSet::new(),
+            auto_connect_pending: false,
         }
     }
 
     pub fn reset(&mut self) {
-        self.is_connected = false;
+        self.connection_state = ConnectionState::Idle;
         self.device_name = None;
         self.device_address = 0;
         self.battery_level = -1;
@@ -103,68 +144,63 @@ impl PluginState {
         #[cfg(windows)]
         {
             self.device = None;
+            self.gatt_session = None;
             self.services.clear();
             self.characteristics.clear();
-            self.notification_handlers.clear();
-            self.connection_status_handler = None;
+            self.notification_tokens.clear();
+            self.connection_status_token = None;
         }
 
+        self.scanning = false;
         self.discovered_devices.clear();
         self.read_cache.clear();
         self.read_first_pass_buffer.clear();
+        self.subscriptions.clear();
     }
 }
 
-#[cfg(windows)]
-pub fn initialize() -> Result<(), windows::core::Error> {
-    use windows::Devices::Bluetooth::Advertisement::{
-        BluetoothLEAdvertisementWatcher,
-        BluetoothLEAdvertisementFilter,
-        BluetoothLEAdvertisement,
+pub fn initialize() -> Result<()> {
+    let backend = {
+        let state = STATE.lock().unwrap();
+        if state.initialized {
+            return Ok(());
+        }
+        state.backend.clone()
     };
-    use windows::core::HSTRING;
 
-    let mut state = STATE.lock().unwrap();
-    if state.initialized {
-        return Ok(());
+    let (config, rejected) = config::load();
+    crate::logging::init(&config);
+    log::info!("Opening plugin v{}", env!("CARGO_PKG_VERSION"));
+    for line in rejected {
+        log::warn!("Ignoring invalid config line: {}", line);
     }
 
-    let watcher = BluetoothLEAdvertisementWatcher::new()?;
-
-    let filter = BluetoothLEAdvertisementFilter::new()?;
-    let adv = BluetoothLEAdvertisement::new()?;
-    adv.SetLocalName(&HSTRING::from("SquareGolf"))?;
-    filter.SetAdvertisement(&adv)?;
-    watcher.SetAdvertisementFilter(&filter)?;
+    backend.open()?;
 
-    state.watcher = Some(watcher);
-    state.initialized = true;
+    {
+        let mut state = STATE.lock().unwrap();
+        state.scan_filter = ScanFilter::from_config(&config);
+        state.auto_connect_pending = config.auto_connect_last != AutoConnect::Off;
+        state.config = config;
+        state.initialized = true;
+    }
 
+    last_device::auto_connect(AutoConnect::Open);
     Ok(())
 }
 
-#[cfg(not(windows))]
-pub fn initialize() -> Result<(), ()> {
-    Err(())
-}
-
 pub fn cleanup() {
-    let mut state = STATE.lock().unwrap();
+    let backend = {
+        let state = STATE.lock().unwrap();
+        state.backend.clone()
+    };
 
-    #[cfg(windows)]
-    {
-        if let Some(ref watcher) = state.watcher {
-            let _ = watcher.Stop();
-        }
-        state.watcher = None;
-        state.watcher_handler = None;
-        state.device = None;
-        state.services = HashMap::new();
-        state.characteristics = HashMap::new();
-        state.notification_handlers = HashMap::new();
-        state.connection_status_handler = None;
-    }
+    log::info!("Closing plugin");
+    crate::connection::invalidate();
+    crate::queue::clear();
+    backend.close();
 
+    let mut state = STATE.lock().unwrap();
     state.reset();
     state.initialized = false;
 }
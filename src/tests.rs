@@ -0,0 +1,546 @@
+//! Drives the exported API against scripted `SimBackend`s. The plugin keeps
+//! its state in one global, so every test holds a `Session` and they run one
+//! at a time.
+
+use crate::backend::sim::{SimBackend, SimPeripheral};
+use crate::*;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+const WRITE_UUID: &str = "86602101-6b7e-439a-bdd1-489a3213e9bb";
+const NOTIFY_UUID: &str = "86602102-6b7e-439a-bdd1-489a3213e9bb";
+const WAIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+static SERIAL: Mutex<()> = Mutex::new(());
+static EVENTS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// An open plugin; closes it again and removes its scratch directory when
+/// dropped.
+struct Session {
+    _serial: MutexGuard<'static, ()>,
+    dir: PathBuf,
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        _close();
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// A fresh directory for the state file and log, so tests never touch the
+/// real ones or see each other's.
+fn scratch_dir() -> PathBuf {
+    static NEXT: Mutex<u32> = Mutex::new(0);
+    let mut next = NEXT.lock().unwrap();
+    *next += 1;
+
+    let dir = std::env::temp_dir().join(format!("squaregolf-plugin-test-{}-{}", std::process::id(), next));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Opens the plugin on `peripherals` with every recording handler installed.
+fn open(peripherals: Vec<SimPeripheral>) -> Session {
+    let serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+
+    _close();
+    let dir = scratch_dir();
+    config::install_data_dir(dir.clone());
+    backend::install(Arc::new(SimBackend::new(peripherals)));
+    EVENTS.lock().unwrap().clear();
+
+    _setScanResultHandler(Some(on_scan_result));
+    _setDeviceLostHandler(Some(on_device_lost));
+    _setConnectionStateChangedHandler(Some(on_connection_state));
+    _setServiceDiscoveredHandler(Some(on_service_discovered));
+    _setNotificationHandler(Some(on_notification));
+    _setWriteCompleteHandler(Some(on_write_complete));
+    _setWriteProgressHandler(Some(on_write_progress));
+    assert_eq!(_open(), 0);
+
+    Session { _serial: serial, dir }
+}
+
+/// The stock peripheral, with notifications that arrive quickly enough for tests.
+fn squaregolf() -> SimPeripheral {
+    let mut peripheral = SimPeripheral::squaregolf();
+    for notification in &mut peripheral.notifications {
+        notification.delay = Duration::from_millis(20);
+    }
+    peripheral
+}
+
+fn wide(s: &str) -> Vec<u16> {
+    s.encode_utf16().collect()
+}
+
+unsafe fn from_wide(ptr: *const u16, len: i32) -> String {
+    String::from_utf16_lossy(unsafe { std::slice::from_raw_parts(ptr, len as usize) })
+}
+
+fn record(event: String) {
+    EVENTS.lock().unwrap().push(event);
+}
+
+fn events() -> Vec<String> {
+    EVENTS.lock().unwrap().clone()
+}
+
+fn wait_until(mut condition: impl FnMut() -> bool) -> bool {
+    let deadline = Instant::now() + WAIT_TIMEOUT;
+    while Instant::now() < deadline {
+        if condition() {
+            return true;
+        }
+        std::thread::sleep(Duration::from_millis(5));
+    }
+    condition()
+}
+
+fn wait_for_event(event: &str) -> bool {
+    wait_until(|| events().iter().any(|e| e == event))
+}
+
+unsafe extern "C" fn on_scan_result(addr: *const u16, addr_len: i32, name: *const u16, name_len: i32, rssi: i32) {
+    record(format!("scan {} {} {}", unsafe { from_wide(addr, addr_len) }, unsafe { from_wide(name, name_len) }, rssi));
+}
+
+unsafe extern "C" fn on_device_lost(addr: *const u16, addr_len: i32) {
+    record(format!("lost {}", unsafe { from_wide(addr, addr_len) }));
+}
+
+unsafe extern "C" fn on_connection_state(state: i32) {
+    record(format!("state {}", state));
+}
+
+unsafe extern "C" fn on_service_discovered(service_type: i32, uuid: *const u16, uuid_len: i32) {
+    record(format!("service {} {}", service_type, unsafe { from_wide(uuid, uuid_len) }));
+}
+
+unsafe extern "C" fn on_notification(uuid: *const u16, uuid_len: i32, value: *const u8, value_len: i32) {
+    let value = unsafe { std::slice::from_raw_parts(value, value_len as usize) };
+    record(format!("notify {} {:02x?}", unsafe { from_wide(uuid, uuid_len) }, value));
+}
+
+unsafe extern "C" fn on_write_complete(uuid: *const u16, uuid_len: i32, status: i32) {
+    record(format!("write {} {}", unsafe { from_wide(uuid, uuid_len) }, status));
+}
+
+unsafe extern "C" fn on_write_progress(uuid: *const u16, uuid_len: i32, written: i32, total: i32) {
+    record(format!("progress {} {}/{}", unsafe { from_wide(uuid, uuid_len) }, written, total));
+}
+
+fn connect(target: &str) {
+    let target = wide(target);
+    assert_eq!(unsafe { _connect(target.as_ptr(), target.len() as i32) }, 0);
+}
+
+#[test]
+fn scan_connect_discover_and_notify() {
+    let _session = open(vec![squaregolf()]);
+
+    assert_eq!(_startScan(1), 0);
+    assert!(wait_for_event("scan C0FFEE000001 SquareGolf -55"));
+    assert_eq!(_stopScan(), 0);
+
+    connect("SquareGolf");
+    assert!(wait_until(|| _getConnectionState() == 2));
+    assert_eq!(_discoverServices(), 0);
+    assert_eq!(_getConnectionState(), 4);
+
+    assert!(wait_for_event(&format!("notify {} [11, 02, 01, 2c, 00, a0, 0f]", NOTIFY_UUID)));
+
+    let events = events();
+    let position = |event: &str| events.iter().position(|e| e == event);
+    assert!(position("state 1") < position("state 2"));
+    assert!(position("service 0 86602100-6b7e-439a-bdd1-489a3213e9bb").is_some());
+    assert!(position(&format!("service 1 {}", NOTIFY_UUID)).is_some());
+    assert!(
+        position(&format!("notify {} [11, 01, 00, 64]", NOTIFY_UUID))
+            < position(&format!("notify {} [11, 02, 01, 2c, 00, a0, 0f]", NOTIFY_UUID))
+    );
+}
+
+#[test]
+fn filtered_scan_lists_every_unit_by_address() {
+    let mut second = squaregolf();
+    second.address = 0xC0FFEE000002;
+    let mut other = squaregolf();
+    other.name = "Headphones".to_string();
+    other.address = 0xC0FFEE000003;
+    let _session = open(vec![squaregolf(), second, other]);
+
+    assert_eq!(_startScan(1), 0);
+    assert!(wait_for_event("scan C0FFEE000001 SquareGolf -55"));
+    assert!(wait_for_event("scan C0FFEE000002 SquareGolf -55"));
+    assert_eq!(_stopScan(), 0);
+    assert!(!events().iter().any(|e| e.contains("Headphones")));
+
+    connect("C0FFEE000002");
+    assert!(wait_until(|| _getConnectionState() == 2));
+    assert_eq!(STATE.lock().unwrap().device_address, 0xC0FFEE000002);
+}
+
+fn scan_events() -> Vec<String> {
+    events().into_iter().filter(|e| e.starts_with("scan ")).collect()
+}
+
+#[test]
+fn address_is_not_taken_for_a_name_prefix() {
+    let mut short_name = squaregolf();
+    short_name.name = "C".to_string();
+    let mut silent = squaregolf();
+    silent.address = 0xC0FFEE000002;
+    silent.advertise_for = Some(Duration::ZERO);
+    let _session = open(vec![short_name, silent]);
+
+    assert_eq!(_startScan(0), 0);
+    assert!(wait_for_event("scan C0FFEE000001 C -55"));
+    assert_eq!(_stopScan(), 0);
+
+    connect("C0FFEE000002");
+    assert!(wait_until(|| _getConnectionState() == 2));
+    assert_eq!(STATE.lock().unwrap().device_address, 0xC0FFEE000002);
+}
+
+#[test]
+fn devices_that_stop_advertising_are_lost() {
+    let mut leaving = squaregolf();
+    leaving.address = 0xC0FFEE000002;
+    leaving.advertise_for = Some(Duration::from_millis(150));
+    let _session = open(vec![squaregolf(), leaving]);
+    STATE.lock().unwrap().config.device_timeout = Some(Duration::from_millis(300));
+
+    assert_eq!(_startScan(1), 0);
+    assert!(wait_for_event("scan C0FFEE000002 SquareGolf -55"));
+    assert!(wait_for_event("lost C0FFEE000002"));
+    assert_eq!(_stopScan(), 0);
+
+    assert!(!events().contains(&"lost C0FFEE000001".to_string()));
+    let state = STATE.lock().unwrap();
+    assert!(state.discovered_devices.contains_key(&0xC0FFEE000001));
+    assert!(!state.discovered_devices.contains_key(&0xC0FFEE000002));
+}
+
+#[test]
+fn reported_rssi_is_a_moving_average() {
+    let mut peripheral = squaregolf();
+    peripheral.rssi_cycle = vec![-40, -80];
+    let _session = open(vec![peripheral]);
+    {
+        let mut state = STATE.lock().unwrap();
+        state.config.rssi_window = 2;
+        state.config.report_interval = Duration::ZERO;
+    }
+
+    assert_eq!(_startScan(1), 0);
+    assert!(wait_until(|| scan_events().len() >= 4));
+    assert_eq!(_stopScan(), 0);
+
+    let scans = scan_events();
+    assert_eq!(scans[0], "scan C0FFEE000001 SquareGolf -40");
+    assert!(scans[1..].iter().all(|e| e == "scan C0FFEE000001 SquareGolf -60"));
+}
+
+#[test]
+fn scan_results_are_rate_limited() {
+    let mut peripheral = squaregolf();
+    peripheral.rssi_cycle = vec![-40, -80];
+    let _session = open(vec![peripheral]);
+    {
+        let mut state = STATE.lock().unwrap();
+        state.config.rssi_window = 2;
+        state.config.report_interval = Duration::from_secs(60);
+    }
+
+    assert_eq!(_startScan(1), 0);
+    assert!(wait_until(|| {
+        STATE.lock().unwrap().discovered_devices.get(&0xC0FFEE000001).is_some_and(|d| d.rssi == -60)
+    }));
+    std::thread::sleep(Duration::from_millis(250));
+    assert_eq!(_stopScan(), 0);
+
+    assert_eq!(scan_events(), ["scan C0FFEE000001 SquareGolf -40"]);
+}
+
+#[test]
+fn devices_below_min_rssi_are_not_reported() {
+    let mut weak = squaregolf();
+    weak.address = 0xC0FFEE000002;
+    weak.rssi = -95;
+    let _session = open(vec![squaregolf(), weak]);
+    {
+        let mut state = STATE.lock().unwrap();
+        state.config.min_rssi = Some(-90);
+        state.config.report_interval = Duration::from_secs(60);
+    }
+
+    assert_eq!(_startScan(1), 0);
+    assert!(wait_for_event("scan C0FFEE000001 SquareGolf -55"));
+    assert!(wait_until(|| STATE.lock().unwrap().discovered_devices.contains_key(&0xC0FFEE000002)));
+    std::thread::sleep(Duration::from_millis(250));
+    assert_eq!(_stopScan(), 0);
+
+    assert_eq!(scan_events(), ["scan C0FFEE000001 SquareGolf -55"]);
+}
+
+#[test]
+fn failed_scan_start_is_rolled_back() {
+    let _session = open(vec![squaregolf()]);
+    backend::current().close();
+
+    assert_eq!(_startScan(0), -1);
+    assert_eq!(_getLastError(), Error::NotInitialized.code());
+    assert!(!STATE.lock().unwrap().scanning);
+}
+
+#[test]
+fn disconnect_reports_idle_once() {
+    let _session = open(vec![squaregolf()]);
+
+    connect("C0FFEE000001");
+    assert!(wait_until(|| _getConnectionState() == 2));
+    assert_eq!(_disconnect(), 0);
+
+    assert_eq!(_getConnectionState(), 0);
+    assert_eq!(events(), ["state 1", "state 2", "state 0"]);
+}
+
+#[test]
+fn disconnect_reports_discarded_writes() {
+    let _session = open(vec![squaregolf()]);
+
+    connect("C0FFEE000001");
+    assert!(wait_until(|| _getConnectionState() == 2));
+
+    let uuid = wide(WRITE_UUID);
+    let data = [0x01];
+    let write_event = format!("write {} -1", WRITE_UUID);
+    let write_count = || events().iter().filter(|e| **e == write_event).count();
+
+    let gatt = queue::exclusive();
+    for _ in 0..3 {
+        assert_eq!(unsafe { _write(uuid.as_ptr(), uuid.len() as i32, data.as_ptr(), 1) }, 0);
+    }
+    assert_eq!(_disconnect(), 0);
+
+    // The worker may already hold the first write; the others are discarded
+    // and reported right away.
+    assert!(write_count() >= 2);
+    drop(gatt);
+    assert!(wait_until(|| write_count() == 3));
+}
+
+#[test]
+fn chunked_write_is_capped_at_the_mtu() {
+    let mut peripheral = squaregolf();
+    peripheral.mtu = 23;
+    let _session = open(vec![peripheral]);
+
+    connect("C0FFEE000001");
+    assert!(wait_until(|| _getConnectionState() == 2));
+
+    let uuid = wide(WRITE_UUID);
+    let data: Vec<u8> = (0..50).collect();
+    let complete = format!("write {} 0", WRITE_UUID);
+
+    for (chunk_size, boundaries) in [
+        (0, vec![20, 40, 50]),
+        (100, vec![20, 40, 50]),
+        (8, vec![8, 16, 24, 32, 40, 48, 50]),
+    ] {
+        EVENTS.lock().unwrap().clear();
+        let result = unsafe { _writeChunk(uuid.as_ptr(), uuid.len() as i32, data.as_ptr(), 50, chunk_size) };
+        assert_eq!(result, 0);
+        assert!(wait_for_event(&complete));
+
+        let mut expected: Vec<String> = boundaries
+            .iter()
+            .map(|written| format!("progress {} {}/50", WRITE_UUID, written))
+            .collect();
+        expected.push(complete.clone());
+        assert_eq!(events(), expected, "chunk_size {}", chunk_size);
+
+        let last = boundaries[boundaries.len() - 2];
+        assert_eq!(backend::current().read(WRITE_UUID).unwrap(), data[last..]);
+    }
+}
+
+#[test]
+fn reconnects_and_resubscribes_after_drop() {
+    let mut peripheral = squaregolf();
+    peripheral.drop_after = Some(Duration::from_millis(200));
+    let _session = open(vec![peripheral]);
+    {
+        let mut state = STATE.lock().unwrap();
+        state.config.auto_reconnect = true;
+        state.config.reconnect_delay = Duration::from_millis(10);
+    }
+
+    connect("C0FFEE000001");
+    assert!(wait_until(|| _getConnectionState() == 2));
+    assert_eq!(_discoverServices(), 0);
+
+    // Dropped, reconnected, and notifications flow again on the new link.
+    assert!(wait_until(|| {
+        let events = events();
+        let Some(dropped) = events.iter().position(|e| e == "state 0") else {
+            return false;
+        };
+        events[dropped..].iter().any(|e| e == "state 2")
+            && events[dropped..].iter().any(|e| e.starts_with("notify"))
+    }));
+}
+
+#[test]
+fn second_connect_replaces_the_first() {
+    let _session = open(vec![squaregolf()]);
+
+    connect("C0FFEE000001");
+    assert!(wait_until(|| _getConnectionState() == 2));
+    assert_eq!(_discoverServices(), 0);
+    assert!(wait_until(|| events().iter().any(|e| e.starts_with("notify"))));
+
+    connect("C0FFEE000001");
+    assert!(wait_until(|| _getConnectionState() == 2));
+    EVENTS.lock().unwrap().clear();
+    assert_eq!(_discoverServices(), 0);
+
+    // Notifications of the new connection get through.
+    assert!(wait_until(|| events().iter().any(|e| e.starts_with("notify"))));
+}
+
+/// Connected to a unit whose notify characteristic reads `value`.
+fn open_with_value(value: &[u8]) -> Session {
+    let mut peripheral = squaregolf();
+    peripheral.notifications.clear();
+    peripheral.services[0].characteristics[1].value = value.to_vec();
+    let session = open(vec![peripheral]);
+
+    connect("C0FFEE000001");
+    assert!(wait_until(|| _getConnectionState() == 2));
+    session
+}
+
+#[test]
+fn read_with_plain_buffer() {
+    let _session = open_with_value(&[0x11, 0x01, 0x00, 0x64]);
+    let uuid = wide(NOTIFY_UUID);
+
+    let mut len = 0;
+    let size = unsafe { _read(uuid.as_ptr(), uuid.len() as i32, std::ptr::null_mut(), &mut len) };
+    assert_eq!((size, len), (4, 4));
+
+    let mut buffer = vec![0u8; len as usize];
+    let copied = unsafe { _read(uuid.as_ptr(), uuid.len() as i32, buffer.as_mut_ptr(), &mut len) };
+    assert_eq!(copied, 4);
+    assert_eq!(buffer, [0x11, 0x01, 0x00, 0x64]);
+}
+
+#[test]
+fn read_through_byte_pointer_pointer() {
+    let _session = open_with_value(&[0x11, 0x02, 0x01]);
+    let uuid = wide(NOTIFY_UUID);
+
+    // The game passes the address of its buffer pointer in both passes.
+    let mut data = vec![0u8; 8];
+    let mut inner = data.as_mut_ptr();
+    let outer = &mut inner as *mut *mut u8 as *mut u8;
+
+    let mut len = 0;
+    let size = unsafe { _read(uuid.as_ptr(), uuid.len() as i32, outer, &mut len) };
+    assert_eq!(size, 3);
+
+    let copied = unsafe { _read(uuid.as_ptr(), uuid.len() as i32, outer, &mut len) };
+    assert_eq!((copied, len), (3, 3));
+    assert_eq!(data[..3], [0x11, 0x02, 0x01]);
+    assert_eq!(data[3..], [0; 5]);
+}
+
+#[test]
+fn read_truncates_to_buffer_size() {
+    let _session = open_with_value(&[1, 2, 3, 4, 5]);
+    let uuid = wide(NOTIFY_UUID);
+
+    let mut buffer = [0u8; 2];
+    let mut len = buffer.len() as i32;
+    let copied = unsafe { _read(uuid.as_ptr(), uuid.len() as i32, buffer.as_mut_ptr(), &mut len) };
+    assert_eq!((copied, len), (2, 2));
+    assert_eq!(buffer, [1, 2]);
+}
+
+#[test]
+fn read_of_unknown_characteristic_fails() {
+    let _session = open_with_value(&[]);
+    let uuid = wide("00002a00-0000-1000-8000-00805f9b34fb");
+
+    let mut len = 0;
+    let result = unsafe { _read(uuid.as_ptr(), uuid.len() as i32, std::ptr::null_mut(), &mut len) };
+    assert_eq!(result, -1);
+    assert_eq!(_getLastError(), Error::CharacteristicNotFound.code());
+}
+
+#[test]
+fn concurrent_discovery_waits_for_the_running_one() {
+    use state::{DISCOVERY_DONE, DISCOVERY_IN_PROGRESS};
+
+    let _session = open(vec![squaregolf()]);
+    {
+        let mut state = STATE.lock().unwrap();
+        // Longer than a single GATT request may take.
+        state.config.gatt_timeout = Duration::from_millis(10);
+        state.config.discovery_timeout = Duration::from_millis(200);
+    }
+
+    // Another caller's discovery that finishes in time...
+    *DISCOVERY_IN_PROGRESS.lock().unwrap() = true;
+    let waiter = std::thread::spawn(|| _discoverServices());
+    std::thread::sleep(Duration::from_millis(50));
+    *DISCOVERY_IN_PROGRESS.lock().unwrap() = false;
+    DISCOVERY_DONE.notify_all();
+    assert_eq!(waiter.join().unwrap(), 0);
+
+    // ...and one that does not.
+    *DISCOVERY_IN_PROGRESS.lock().unwrap() = true;
+    assert_eq!(_discoverServices(), -1);
+    assert_eq!(_getLastError(), Error::Timeout.code());
+    *DISCOVERY_IN_PROGRESS.lock().unwrap() = false;
+}
+
+#[test]
+fn read_waits_for_the_running_queued_operation() {
+    let _session = open_with_value(&[0x2a]);
+
+    let (sender, receiver) = std::sync::mpsc::channel();
+    let gatt = queue::exclusive();
+    let reader = std::thread::spawn(move || {
+        let uuid = wide(NOTIFY_UUID);
+        let mut value = [0u8; 1];
+        let mut len = 1;
+        let result = unsafe { _read(uuid.as_ptr(), uuid.len() as i32, value.as_mut_ptr(), &mut len) };
+        sender.send((result, value)).unwrap();
+    });
+
+    assert!(receiver.recv_timeout(Duration::from_millis(50)).is_err());
+    drop(gatt);
+    assert_eq!(receiver.recv_timeout(WAIT_TIMEOUT).unwrap(), (1, [0x2a]));
+    reader.join().unwrap();
+}
+
+#[test]
+fn timed_scan_connects_to_the_remembered_device() {
+    let _session = open(vec![squaregolf()]);
+    last_device::save(0xC0FFEE000001, "SquareGolf");
+    {
+        let mut state = STATE.lock().unwrap();
+        state.config.auto_connect_last = last_device::AutoConnect::Scan;
+        state.auto_connect_pending = true;
+    }
+
+    assert_eq!(_startScanWithTimeout(1, 0, 0), 0);
+    assert!(wait_until(|| _getConnectionState() == 2));
+    assert_eq!(STATE.lock().unwrap().device_address, 0xC0FFEE000001);
+}
@@ -1,24 +1,61 @@
-use crate::callbacks::invoke_scan_result;
+use crate::backend::{Advertisement, AdvertisementData, DataSection, ManufacturerData};
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::scan::ScanMode;
 use crate::state::STATE;
+use windows::core::Interface;
 use windows::Devices::Bluetooth::Advertisement::{
     BluetoothLEAdvertisementReceivedEventArgs,
     BluetoothLEAdvertisementWatcher,
+    BluetoothLEAdvertisementWatcherStatus,
+    BluetoothLEAdvertisementWatcherStoppedEventArgs,
+    BluetoothLEScanningMode,
 };
-use windows::Foundation::TypedEventHandler;
+use windows::Devices::Bluetooth::BluetoothError;
+use windows::Foundation::{IReference, PropertyValue, TimeSpan, TypedEventHandler};
+use windows::Storage::Streams::{DataReader, IBuffer};
 
-pub fn start_scan() -> Result<(), windows::core::Error> {
+pub fn create_watcher() -> Result<()> {
+    let watcher = BluetoothLEAdvertisementWatcher::new()?;
+
+    let handler = TypedEventHandler::new(
+        |sender: &Option<BluetoothLEAdvertisementWatcher>,
+         args: &Option<BluetoothLEAdvertisementWatcherStoppedEventArgs>| {
+            handle_watcher_stopped(sender.as_ref(), args.as_ref());
+            Ok(())
+        },
+    );
+    let token = watcher.Stopped(&handler)?;
+
+    let mut state = STATE.lock().unwrap();
+    state.watcher = Some(watcher);
+    state.watcher_stopped_token = Some(token);
+
+    Ok(())
+}
+
+pub fn destroy_watcher() {
+    stop_scan();
+
+    let (watcher, token) = {
+        let mut state = STATE.lock().unwrap();
+        (state.watcher.take(), state.watcher_stopped_token.take())
+    };
+
+    if let (Some(watcher), Some(token)) = (watcher, token) {
+        let _ = watcher.RemoveStopped(token);
+    }
+}
+
+pub fn start_scan() -> Result<()> {
     let mut state = STATE.lock().unwrap();
 
-    if state.watcher_handler.is_some() {
+    if state.watcher_token.is_some() {
         return Ok(());
     }
 
-    let watcher = state.watcher.as_ref().ok_or_else(|| {
-        windows::core::Error::new(
-            windows::core::HRESULT(-1),
-            "Watcher not initialized",
-        )
-    })?;
+    let watcher = state.watcher.as_ref().ok_or(Error::NotInitialized)?;
+    configure_watcher(watcher, &state.config);
 
     let handler = TypedEventHandler::new(
         |_sender: &Option<BluetoothLEAdvertisementWatcher>,
@@ -30,54 +67,135 @@ pub fn start_scan() -> Result<(), windows::core::Error> {
         },
     );
 
-    watcher.Received(&handler)?;
-    watcher.Start()?;
+    let token = watcher.Received(&handler)?;
+    if let Err(e) = watcher.Start() {
+        let _ = watcher.RemoveReceived(token);
+        return Err(e.into());
+    }
 
-    state.watcher_handler = Some(send_wrapper::SendWrapper::new(handler));
+    state.watcher_token = Some(token);
 
     Ok(())
 }
 
+/// Applies the `[scan]` watcher settings. The watcher only accepts them while
+/// stopped, so this runs before every `Start`. Settings the stack (or Wine)
+/// does not support are logged and skipped rather than failing the scan.
+fn configure_watcher(watcher: &BluetoothLEAdvertisementWatcher, config: &Config) {
+    let mode = match config.scan_mode {
+        ScanMode::Active => BluetoothLEScanningMode::Active,
+        ScanMode::Passive => BluetoothLEScanningMode::Passive,
+    };
+    if let Err(e) = watcher.SetScanningMode(mode) {
+        log::warn!("Could not set scanning mode {:?}: {}", config.scan_mode, e);
+    }
+
+    if let Some(interval) = config.sampling_interval {
+        let result = watcher.SignalStrengthFilter().and_then(|filter| {
+            let interval = TimeSpan { Duration: interval.as_nanos() as i64 / 100 };
+            let value: IReference<TimeSpan> = PropertyValue::CreateTimeSpan(interval)?.cast()?;
+            filter.SetSamplingInterval(&value)?;
+            watcher.SetSignalStrengthFilter(&filter)
+        });
+        if let Err(e) = result {
+            log::warn!("Could not set sampling interval {:?}: {}", interval, e);
+        }
+    }
+
+    if let Err(e) = watcher.SetAllowExtendedAdvertisements(config.allow_extended_advertisements) {
+        log::warn!("Could not set extended advertisements: {}", e);
+    }
+}
+
+/// Stops the watcher and unregisters the `Received` handler, so a later
+/// `start_scan` does not stack a second delivery of every advertisement.
 pub fn stop_scan() {
-    let mut state = STATE.lock().unwrap();
-    if let Some(ref watcher) = state.watcher {
+    let (watcher, token) = {
+        let mut state = STATE.lock().unwrap();
+        (state.watcher.clone(), state.watcher_token.take())
+    };
+
+    if let Some(watcher) = watcher {
         let _ = watcher.Stop();
+        if let Some(token) = token {
+            let _ = watcher.RemoveReceived(token);
+        }
+    }
+}
+
+/// `Stopped` fires both after `stop_scan` and when the stack aborts the scan
+/// on its own (radio switched off, adapter removed, ...). Stops the plugin
+/// asked for are already reported by `scan::stop_scan`, and their event may
+/// arrive after the scan was restarted, so only aborts are handled here. They
+/// leave the `Received` registration behind, which is dropped to let the next
+/// `start_scan` start the watcher again.
+fn handle_watcher_stopped(
+    watcher: Option<&BluetoothLEAdvertisementWatcher>,
+    args: Option<&BluetoothLEAdvertisementWatcherStoppedEventArgs>,
+) {
+    let error = args.and_then(|a| a.Error().ok()).unwrap_or(BluetoothError::Success);
+    let aborted = watcher.and_then(|w| w.Status().ok())
+        == Some(BluetoothLEAdvertisementWatcherStatus::Aborted);
+
+    if error == BluetoothError::Success && !aborted {
+        return;
     }
-    state.watcher_handler = None;
+
+    stop_scan();
+    crate::scan::handle_scan_stopped(error.0);
 }
 
 fn handle_advertisement_received(args: &BluetoothLEAdvertisementReceivedEventArgs) {
     let address = args.BluetoothAddress().unwrap_or(0);
     let rssi = args.RawSignalStrengthInDBm().unwrap_or(-100) as i32;
 
-    let name = if let Ok(adv) = args.Advertisement() {
-        adv.LocalName()
-            .map(|s| s.to_string_lossy())
-            .unwrap_or_default()
-    } else {
-        String::new()
+    let mut name = String::new();
+    let mut data = AdvertisementData {
+        tx_power: args.TransmitPowerLevelInDBm().and_then(|p| p.Value()).ok(),
+        ..Default::default()
     };
 
-    if name.is_empty() {
-        return;
-    }
-
-    {
-        let mut state = STATE.lock().unwrap();
-        state.discovered_devices.insert(name.clone(), address);
+    if let Ok(adv) = args.Advertisement() {
+        name = adv.LocalName()
+            .map(|s| s.to_string_lossy())
+            .unwrap_or_default();
+
+        if let Ok(entries) = adv.ManufacturerData() {
+            for entry in entries {
+                if let (Ok(company_id), Ok(buffer)) = (entry.CompanyId(), entry.Data()) {
+                    data.manufacturer_data.push(ManufacturerData {
+                        company_id,
+                        data: read_buffer(&buffer),
+                    });
+                }
+            }
+        }
+
+        if let Ok(uuids) = adv.ServiceUuids() {
+            data.service_uuids = uuids.into_iter().map(|g| crate::gatt::format_uuid(&g)).collect();
+        }
+
+        if let Ok(sections) = adv.DataSections() {
+            for section in sections {
+                if let (Ok(data_type), Ok(buffer)) = (section.DataType(), section.Data()) {
+                    data.data_sections.push(DataSection {
+                        data_type,
+                        data: read_buffer(&buffer),
+                    });
+                }
+            }
+        }
     }
 
-    let addr_str = format!("{:012X}", address);
-
-    let handler = {
-        let state = STATE.lock().unwrap();
-        state.scan_result_handler
-    };
-
-    invoke_scan_result(handler, &addr_str, &name, rssi);
+    crate::scan::handle_advertisement(Advertisement { address, name, rssi, data });
 }
 
-pub fn get_address_for_name(name: &str) -> Option<u64> {
-    let state = STATE.lock().unwrap();
-    state.discovered_devices.get(name).copied()
+fn read_buffer(buffer: &IBuffer) -> Vec<u8> {
+    let read = || -> windows::core::Result<Vec<u8>> {
+        let reader = DataReader::FromBuffer(buffer)?;
+        let mut data = vec![0u8; reader.UnconsumedBufferLength()? as usize];
+        reader.ReadBytes(&mut data)?;
+        Ok(data)
+    };
+    read().unwrap_or_default()
 }